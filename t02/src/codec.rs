@@ -0,0 +1,238 @@
+use crate::error::{Error, Result};
+use std::fmt::{self, Debug};
+
+//A value stored in a fixed-size slot of a page, always WIDTH bytes on disk
+pub trait FixedWidth: Copy + Debug {
+    const WIDTH: usize;
+
+    //Appends exactly WIDTH bytes to the buffer
+    fn encode(&self, buffer: &mut Vec<u8>);
+
+    //The buffer always has exactly WIDTH bytes
+    fn decode(buffer: &[u8]) -> Self;
+
+    //How print_all_table shows the value
+    fn to_display(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+//A key of the index, hash_code is the number the buckets are chosen from
+pub trait HashKey: FixedWidth + PartialEq {
+    fn hash_code(&self) -> u64;
+}
+
+//Text of at most N bytes of UTF-8, padded with zeros to exactly N bytes. The text cannot have
+//a '\0', so the padding is never taken as part of it. Used for the nome of the registers and
+//of the items of alternative 1
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ByteString<const N: usize> {
+    bytes: [u8; N],
+}
+
+#[allow(dead_code)]
+impl<const N: usize> ByteString<N> {
+    //Fails when the text has more than N bytes or has a '\0'
+    pub fn new(text: &str) -> Result<ByteString<N>> {
+        let mut bytes = Vec::with_capacity(N);
+        pad_text(text, N, &mut bytes)?;
+        Ok(ByteString {
+            bytes: bytes.try_into().unwrap(),
+        })
+    }
+
+    //Keeps as many whole chars as fit in N bytes, up to the first '\0'
+    pub fn truncated(text: &str) -> ByteString<N> {
+        let text = text.split('\0').next().unwrap_or_default();
+        let mut end = text.len().min(N);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        ByteString::new(&text[..end]).unwrap()
+    }
+
+    //A corrupt string that is not UTF-8 only shows the part before the first bad byte
+    pub fn as_str(&self) -> &str {
+        let text = &self.bytes[..text_length(&self.bytes)];
+        match std::str::from_utf8(text) {
+            Ok(text) => text,
+            Err(error) => std::str::from_utf8(&text[..error.valid_up_to()]).unwrap(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.bytes
+    }
+
+    //Bytes of the text, without the padding
+    pub fn len(&self) -> usize {
+        text_length(&self.bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize> Default for ByteString<N> {
+    fn default() -> ByteString<N> {
+        ByteString { bytes: [0; N] }
+    }
+}
+
+impl<const N: usize> fmt::Debug for ByteString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Display for ByteString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<const N: usize> FixedWidth for ByteString<N> {
+    const WIDTH: usize = N;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.bytes);
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        ByteString {
+            bytes: buffer.try_into().unwrap(),
+        }
+    }
+
+    fn to_display(&self) -> String {
+        self.as_str().to_owned()
+    }
+}
+
+impl<const N: usize> HashKey for ByteString<N> {
+    fn hash_code(&self) -> u64 {
+        self.bytes.hash_code()
+    }
+}
+
+//Appends the text and then zeros up to width bytes, the rules of ByteString
+pub fn pad_text(text: &str, width: usize, buffer: &mut Vec<u8>) -> Result<()> {
+    if text.len() > width {
+        return Err(Error::InvalidRow(format!(
+            "text of {} bytes does not fit in {} bytes",
+            text.len(),
+            width
+        )));
+    }
+    if text.contains('\0') {
+        return Err(Error::InvalidRow(
+            "fixed width text cannot have a '\\0'".to_owned(),
+        ));
+    }
+    buffer.extend_from_slice(text.as_bytes());
+    buffer.resize(buffer.len() + width - text.len(), 0);
+    Ok(())
+}
+
+//Text of a padded field, None if it is not UTF-8
+pub fn unpad_text(bytes: &[u8]) -> Option<&str> {
+    std::str::from_utf8(&bytes[..text_length(bytes)]).ok()
+}
+
+//The padding starts at the first '\0'
+fn text_length(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len())
+}
+
+impl FixedWidth for u32 {
+    const WIDTH: usize = 4;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        u32::from_be_bytes(buffer.try_into().unwrap())
+    }
+}
+
+impl HashKey for u32 {
+    fn hash_code(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl FixedWidth for u64 {
+    const WIDTH: usize = 8;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        u64::from_be_bytes(buffer.try_into().unwrap())
+    }
+}
+
+impl HashKey for u64 {
+    fn hash_code(&self) -> u64 {
+        *self
+    }
+}
+
+//Fixed-length strings, e.g. a nome or a code padded to N bytes
+impl<const N: usize> FixedWidth for [u8; N] {
+    const WIDTH: usize = N;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(self);
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        buffer.try_into().unwrap()
+    }
+
+    fn to_display(&self) -> String {
+        String::from_utf8_lossy(self).into_owned()
+    }
+}
+
+impl<const N: usize> HashKey for [u8; N] {
+    fn hash_code(&self) -> u64 {
+        self.iter().fold(0u64, |code, &byte| {
+            code.wrapping_mul(31).wrapping_add(byte as u64)
+        })
+    }
+}
+
+//Composite keys and values, the first field is written before the second
+impl<A: FixedWidth, B: FixedWidth> FixedWidth for (A, B) {
+    const WIDTH: usize = A::WIDTH + B::WIDTH;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.0.encode(buffer);
+        self.1.encode(buffer);
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        let (first, second) = buffer.split_at(A::WIDTH);
+        (A::decode(first), B::decode(second))
+    }
+
+    fn to_display(&self) -> String {
+        format!("({}, {})", self.0.to_display(), self.1.to_display())
+    }
+}
+
+impl<A: HashKey, B: HashKey> HashKey for (A, B) {
+    fn hash_code(&self) -> u64 {
+        self.0
+            .hash_code()
+            .wrapping_mul(31)
+            .wrapping_add(self.1.hash_code())
+    }
+}
@@ -1,362 +1,1515 @@
-use std::fs::{OpenOptions};
+use crate::codec::{ByteString, FixedWidth, HashKey};
+use crate::error::{Error, Result};
+use crate::hash_function::HashFunction;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
 
+const MAGIC: &[u8; 8] = b"T02-HASH";
+const FORMAT_VERSION: u32 = 8;
+//Pages have room for ITEMS_PER_PAGE items of alternative 1 (4 bytes of key + 96 bytes of nome),
+//narrower values (e.g. a rid in alternative 2) fit more items in the same page
 const ITEMS_PER_PAGE: usize = 8;
-const EMPTY_ITEM_KEY: u32 = 0xffffffff;
-const EMPTY_ITEM_VALUE: [char; 96] = ['x'; 96];
+const ITEM_SIZE: usize = 100;
+//kind (u8) + local depth (u32) + bucket (u32) + next overflow page (u64) + item count (u32),
+//followed by the occupancy bitmap with one bit per slot
+const PAGE_HEADER_SIZE: usize = 21;
+const PAGE_SIZE: usize = PAGE_HEADER_SIZE + ITEMS_PER_PAGE.div_ceil(8) + ITEM_SIZE * ITEMS_PER_PAGE;
+const HEADER_PAGE: u64 = 0; // The first page of the file describes the table, buckets come after it
+const MAX_GLOBAL_DEPTH: u32 = 32; // The directory never uses more than 32 bits of the hash code
+const DEFAULT_MAX_DIRECTORY_DEPTH: u32 = 16; // Splits stop doubling the directory here, see set_max_directory_depth
+const NO_PAGE: u64 = u64::MAX; // End of an overflow chain
+const DEFAULT_MERGE_THRESHOLD: u32 = 50; // Percent of the slots of a page
+const BULK_LOAD_FACTOR: usize = 75; // Percent of the slots a presized table starts filling
 
 #[derive(Clone, Copy, Debug)]
-pub struct Item {
-    key: u32,
-    value: [char; 96],
+pub struct Item<K, V> {
+    key: K,
+    value: V,
 }
 
+#[allow(dead_code)]
+impl<K: HashKey, V: FixedWidth> Item<K, V> {
+    pub fn get_key(&self) -> K {
+        self.key
+    }
+
+    pub fn get_value(&self) -> V {
+        self.value
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PageKind {
+    Free = 0,
+    Primary = 1,
+    Overflow = 2,
+}
+
+//A bucket is one page of the file: its kind, the local depth, which bucket it belongs to,
+//the next page of its overflow chain and as many items as fit in the rest of the page
+#[derive(Clone, Debug)]
+struct Bucket<K, V> {
+    kind: PageKind,
+    local_depth: u32,
+    bucket: u32, // Directory bits shared by its keys (extendible) or bucket number (linear)
+    next: u64,
+    count: usize, // Occupied slots, kept in the page header next to the bitmap
+    items: Vec<Option<Item<K, V>>>, // None is an empty slot
+}
+
+impl<K: HashKey, V: FixedWidth> Bucket<K, V> {
+    //Each slot takes its item plus one bit of the bitmap
+    const SLOTS: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) * 8 / ((K::WIDTH + V::WIDTH) * 8 + 1);
+    const BITMAP_SIZE: usize = Self::SLOTS.div_ceil(8);
+
+    fn empty(kind: PageKind, bucket: u32, local_depth: u32) -> Bucket<K, V> {
+        Bucket {
+            kind,
+            local_depth,
+            bucket,
+            next: NO_PAGE,
+            count: 0,
+            items: vec![None; Self::SLOTS],
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == Self::SLOTS
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        if self.is_full() {
+            return None;
+        }
+        self.items.iter().position(|item| item.is_none())
+    }
+
+    fn put(&mut self, slot: usize, item: Item<K, V>) {
+        if self.items[slot].replace(item).is_none() {
+            self.count += 1;
+        }
+    }
+
+    fn take(&mut self, slot: usize) {
+        if self.items[slot].take().is_some() {
+            self.count -= 1;
+        }
+    }
+
+    fn position(&self, key: &K) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        self.items
+            .iter()
+            .position(|item| item.is_some_and(|item| item.key == *key))
+    }
+
+    //Only goes up to the last occupied slot, the bytes of empty slots are never written
+    fn to_bytes(&self) -> Vec<u8> {
+        let item_size = K::WIDTH + V::WIDTH;
+        let used_slots = self
+            .items
+            .iter()
+            .rposition(|item| item.is_some())
+            .map_or(0, |slot| slot + 1);
+        let mut buffer =
+            Vec::with_capacity(PAGE_HEADER_SIZE + Self::BITMAP_SIZE + used_slots * item_size);
+        buffer.push(self.kind as u8);
+        buffer.extend_from_slice(&self.local_depth.to_be_bytes());
+        buffer.extend_from_slice(&self.bucket.to_be_bytes());
+        buffer.extend_from_slice(&self.next.to_be_bytes());
+        buffer.extend_from_slice(&(self.count as u32).to_be_bytes());
+        let mut bitmap = vec![0u8; Self::BITMAP_SIZE];
+        for (slot, item) in self.items.iter().enumerate() {
+            if item.is_some() {
+                bitmap[slot / 8] |= 1 << (slot % 8);
+            }
+        }
+        buffer.extend_from_slice(&bitmap);
+        for item in &self.items[..used_slots] {
+            match item {
+                Some(item) => {
+                    item.key.encode(&mut buffer);
+                    item.value.encode(&mut buffer);
+                }
+                None => buffer.resize(buffer.len() + item_size, 0),
+            }
+        }
+        buffer
+    }
+
+    fn from_bytes(page: u64, buffer: &[u8; PAGE_SIZE]) -> Result<Bucket<K, V>> {
+        let (header_buf, rest) = buffer.split_at(PAGE_HEADER_SIZE);
+        let (bitmap, items_buf) = rest.split_at(Self::BITMAP_SIZE);
+        let kind = match header_buf[0] {
+            0 => PageKind::Free,
+            1 => PageKind::Primary,
+            2 => PageKind::Overflow,
+            _ => {
+                return Err(Error::CorruptPage {
+                    page,
+                    reason: "unknown page kind",
+                })
+            }
+        };
+        let mut bucket = Bucket::empty(
+            kind,
+            u32::from_be_bytes(header_buf[5..9].try_into().unwrap()),
+            u32::from_be_bytes(header_buf[1..5].try_into().unwrap()),
+        );
+        bucket.next = u64::from_be_bytes(header_buf[9..17].try_into().unwrap());
+        let count = u32::from_be_bytes(header_buf[17..21].try_into().unwrap()) as usize;
+        for (slot, chunk) in items_buf
+            .chunks_exact(K::WIDTH + V::WIDTH)
+            .take(Self::SLOTS)
+            .enumerate()
+        {
+            if bitmap[slot / 8] & (1 << (slot % 8)) != 0 {
+                let (key_buf, value_buf) = chunk.split_at(K::WIDTH);
+                bucket.put(
+                    slot,
+                    Item {
+                        key: K::decode(key_buf),
+                        value: V::decode(value_buf),
+                    },
+                );
+            }
+        }
+        if bucket.count != count {
+            return Err(Error::CorruptPage {
+                page,
+                reason: "item count does not match the occupancy bitmap",
+            });
+        }
+        Ok(bucket)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashMode {
+    //Directory of 2^global_depth pointers, only the overflowing bucket is split
+    Extendible,
+    //No directory, buckets are split one at a time in round-robin order by the split pointer
+    Linear,
+}
+
+//Decides what happens when an insert finds every page of its bucket chain full
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitPolicy {
+    //Split as soon as the primary page is full, overflow pages are only used when splitting
+    //cannot separate the items (linear mode splits whenever an overflow page is created)
+    Immediate,
+    //Chain up to this many overflow pages to a bucket before a split is triggered
+    MaxChainLength(usize),
+}
+
+impl SplitPolicy {
+    fn max_overflow_pages(&self) -> usize {
+        match self {
+            SplitPolicy::Immediate => 0,
+            SplitPolicy::MaxChainLength(length) => *length,
+        }
+    }
+
+    //Stored in the header as a tag and the chain length
+    fn to_header(self) -> (u8, u64) {
+        match self {
+            SplitPolicy::Immediate => (0, 0),
+            SplitPolicy::MaxChainLength(length) => (1, length as u64),
+        }
+    }
+
+    fn from_header(tag: u8, length: u64) -> Result<SplitPolicy> {
+        match tag {
+            0 => Ok(SplitPolicy::Immediate),
+            1 => Ok(SplitPolicy::MaxChainLength(length as usize)),
+            _ => Err(Error::FormatMismatch(
+                "unknown split policy in hash table header",
+            )),
+        }
+    }
+}
+
+//Extendible hashing: the directory has 2^global_depth entries, each pointing to a bucket page.
+//Several entries may share a bucket while its local depth is smaller than the global depth.
+//Linear hashing: the directory holds exactly one entry per bucket, hashed with
+//key % (initial_capacity * 2^level), or with the next level for buckets already split this round.
+//K is the key and V the value kept next to it: the nome itself (alternative 1) or a rid
+//(alternative 2), any type with a fixed width on disk works for both
 #[derive(Debug)]
-pub struct DynamicHashTable {
+pub struct DynamicHashTable<K = u32, V = ByteString<96>> {
     size: usize,
-    capacity: usize,
+    capacity: usize, // Number of buckets, each one has a primary page in the file
+    mode: HashMode,
+    split_policy: SplitPolicy,
+    hash_function: HashFunction,
+    merge_threshold: u32, // Buddy buckets merge when their items fit in this percent of a page
+    max_directory_depth: u32, // Global depth past which full buckets chain overflow pages instead
+    splits: u64,
+    merges: u64,
+    resizes: u64, // Times the directory doubled or halved (extendible) or the level changed (linear)
+    global_depth: u32,
+    directory: Vec<u64>, // directory[hash(key)] = page number of the bucket
+    initial_capacity: usize,
+    level: u32,
+    split_pointer: usize, // Next bucket to be split in linear mode
+    page_count: u64,      // Pages in the file, counting the header page
+    free_pages: Vec<u64>, // Overflow pages released by splits and removals, reused before growing the file
     file_name: String,
+    items: PhantomData<Item<K, V>>,
 }
 
 #[allow(dead_code)]
-impl DynamicHashTable {
-    pub fn new(
+impl<K: HashKey, V: FixedWidth> DynamicHashTable<K, V> {
+    //The initial capacity is rounded up to a power of two, one bucket per directory entry
+    pub fn new(initial_capacity: usize, file_name: String) -> Result<DynamicHashTable<K, V>> {
+        DynamicHashTable::new_with_mode(initial_capacity, file_name, HashMode::Extendible)
+    }
+
+    pub fn new_with_mode(
+        initial_capacity: usize,
+        file_name: String,
+        mode: HashMode,
+    ) -> Result<DynamicHashTable<K, V>> {
+        DynamicHashTable::new_with_hash_function(
+            initial_capacity,
+            file_name,
+            mode,
+            HashFunction::Modulo,
+        )
+    }
+
+    //The hash function cannot change later, every item would have to move
+    pub fn new_with_hash_function(
         initial_capacity: usize,
         file_name: String,
-    ) -> Result<DynamicHashTable, &'static str> {  
-        let _table = match OpenOptions::new()
+        mode: HashMode,
+        hash_function: HashFunction,
+    ) -> Result<DynamicHashTable<K, V>> {
+        let (global_depth, capacity) = match mode {
+            HashMode::Extendible => {
+                let global_depth = initial_capacity.max(1).next_power_of_two().trailing_zeros();
+                (global_depth, 1usize << global_depth)
+            }
+            HashMode::Linear => (0, initial_capacity.max(1)),
+        };
+        let mut file = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(file_name.clone())
+            .truncate(true)
+            .open(&file_name)?;
+
+        let table = DynamicHashTable {
+            size: 0,
+            capacity,
+            mode,
+            split_policy: SplitPolicy::Immediate,
+            hash_function,
+            merge_threshold: DEFAULT_MERGE_THRESHOLD,
+            max_directory_depth: DEFAULT_MAX_DIRECTORY_DEPTH,
+            splits: 0,
+            merges: 0,
+            resizes: 0,
+            global_depth,
+            directory: (1..=capacity as u64).collect(),
+            initial_capacity: capacity,
+            level: 0,
+            split_pointer: 0,
+            page_count: capacity as u64 + 1,
+            free_pages: Vec::new(),
+            file_name,
+            items: PhantomData,
+        };
+
+        //Save a empty hash table to the file, every bucket starts with the global depth
+        let mut buffer: Vec<u8> = Vec::with_capacity((capacity + 1) * PAGE_SIZE);
+        buffer.append(&mut table.header_bytes());
+        for bucket in 0..capacity as u32 {
+            let mut page =
+                Bucket::<K, V>::empty(PageKind::Primary, bucket, global_depth).to_bytes();
+            page.resize(PAGE_SIZE, 0);
+            buffer.append(&mut page);
+        }
+        file.write_all(&buffer)?;
+        Ok(table)
+    }
+
+    //Enough buckets for the expected items to fill BULK_LOAD_FACTOR percent of their primary
+    //pages, so loading them causes no splits as long as the hash spreads the keys evenly
+    pub fn capacity_for(expected_items: usize) -> usize {
+        (expected_items * 100)
+            .div_ceil(Bucket::<K, V>::SLOTS * BULK_LOAD_FACTOR)
+            .max(1)
+    }
+
+    //Reopens a table saved by a previous run, the directory (or the bucket list in linear
+    //mode) is rebuilt from the bucket number kept in every primary page
+    pub fn open(file_name: String) -> Result<DynamicHashTable<K, V>> {
+        let mut file = OpenOptions::new().read(true).open(&file_name)?;
+        let file_length = file.metadata()?.len();
+        let mut buffer = [0u8; PAGE_SIZE];
+        if file_length < PAGE_SIZE as u64 || file.read_exact(&mut buffer).is_err() {
+            return Err(Error::FormatMismatch(
+                "file is too small to be a hash table",
+            ));
+        }
+        if &buffer[0..8] != MAGIC {
+            return Err(Error::FormatMismatch("file is not a hash table"));
+        }
+        let u32_at = |pos: usize| u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_be_bytes(buffer[pos..pos + 8].try_into().unwrap());
+        if u32_at(8) != FORMAT_VERSION {
+            return Err(Error::FormatMismatch(
+                "unsupported hash table format version",
+            ));
+        }
+        if u32_at(12) as usize != PAGE_SIZE
+            || u32_at(16) as usize != Bucket::<K, V>::SLOTS
+            || u32_at(20) as usize != K::WIDTH + V::WIDTH
+            || u32_at(82) as usize != K::WIDTH
         {
-            //Save a empty hash table to the file
-            //The size is the "initial_capacity"
-            Ok(mut file) => {
-                let mut buffer: Vec<u8> = Vec::new();
-                for _i in 0..initial_capacity {
-                    for _j in 0..ITEMS_PER_PAGE {
-                        let mut empty_key = EMPTY_ITEM_KEY.to_be_bytes().to_vec();
-                        let mut empty_value =
-                            EMPTY_ITEM_VALUE.iter().collect::<String>().into_bytes();
-                        buffer.append(&mut empty_key); //Rust doesn't support NULL so
-                        buffer.append(&mut empty_value); //This is my workaround
-                    }
-                }
-                match file.write_all(&buffer) {
-                    Ok(_) => {
-                        return Ok(DynamicHashTable {
-                            size: 0,
-                            capacity: initial_capacity,
-                            file_name,
-                        })
-                    }
-                    Err(_) => {
-                        return Err("Error writing empty table with initial capacity to file")
-                    }
-                }
+            return Err(Error::FormatMismatch(
+                "hash table page layout does not match",
+            ));
+        }
+        let mode = match buffer[24] {
+            0 => HashMode::Extendible,
+            1 => HashMode::Linear,
+            _ => {
+                return Err(Error::FormatMismatch(
+                    "unknown hash mode in hash table header",
+                ))
             }
-            Err(_) => return Err("Error creating hash table"),
         };
-    }
+        let mut table = DynamicHashTable {
+            size: u64_at(34) as usize,
+            capacity: u64_at(42) as usize,
+            mode,
+            split_policy: SplitPolicy::from_header(buffer[25], u64_at(26))?,
+            hash_function: HashFunction::from_header(buffer[86], u64_at(87))?,
+            merge_threshold: u32_at(95),
+            max_directory_depth: u32_at(123),
+            splits: u64_at(99),
+            merges: u64_at(107),
+            resizes: u64_at(115),
+            global_depth: u32_at(50),
+            directory: Vec::new(),
+            initial_capacity: u64_at(54) as usize,
+            level: u32_at(62),
+            split_pointer: u64_at(66) as usize,
+            page_count: u64_at(74),
+            free_pages: Vec::new(),
+            file_name,
+            items: PhantomData,
+        };
+        if table.page_count == 0
+            || file_length < table.page_count * PAGE_SIZE as u64
+            || table.capacity as u64 >= table.page_count
+            || table.global_depth > MAX_GLOBAL_DEPTH
+            || table.initial_capacity == 0
+            || table.merge_threshold > 100
+            || table.max_directory_depth > MAX_GLOBAL_DEPTH
+        {
+            return Err(Error::FormatMismatch(
+                "hash table header does not match the file",
+            ));
+        }
+        if mode == HashMode::Linear
+            && (table.initial_capacity as u64)
+                .checked_shl(table.level)
+                .map(|round| round + table.split_pointer as u64)
+                != Some(table.capacity as u64)
+        {
+            return Err(Error::FormatMismatch(
+                "linear hashing level does not match the capacity",
+            ));
+        }
 
-    pub fn print_all_table(&self) { //Intended for debbuging
-        let mut file = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(self.file_name.clone())
+        //Only the bucket headers are needed: which pages are free and where each bucket starts
+        let mut primary_pages = Vec::with_capacity(table.capacity);
+        for page in HEADER_PAGE + 1..table.page_count {
+            let bucket = table.read_bucket(&mut file, page)?;
+            match bucket.kind {
+                PageKind::Free => table.free_pages.push(page),
+                PageKind::Overflow => {}
+                PageKind::Primary => primary_pages.push((page, bucket.bucket, bucket.local_depth)),
+            }
+        }
+        //Some bucket always uses every bit of the directory, this also keeps a corrupt
+        //global depth from allocating a huge directory
+        let deepest = primary_pages.iter().map(|(_, _, depth)| *depth).max();
+        if primary_pages.len() != table.capacity
+            || (mode == HashMode::Extendible && deepest != Some(table.global_depth))
         {
+            return Err(Error::FormatMismatch(
+                "hash table header does not match its buckets",
+            ));
+        }
+
+        table.directory = match mode {
+            HashMode::Extendible => vec![NO_PAGE; 1usize << table.global_depth],
+            HashMode::Linear => vec![NO_PAGE; table.capacity],
+        };
+        for (page, first, local_depth) in primary_pages {
+            let step = match mode {
+                HashMode::Extendible => 1usize << local_depth,
+                HashMode::Linear => table.directory.len(),
+            };
+            if first as usize >= table.directory.len() {
+                return Err(Error::CorruptPage {
+                    page,
+                    reason: "bucket number outside of the directory",
+                });
+            }
+            for index in (first as usize..table.directory.len()).step_by(step) {
+                table.directory[index] = page;
+            }
+        }
+        if table.directory.contains(&NO_PAGE) {
+            return Err(Error::FormatMismatch(
+                "hash table directory has missing buckets",
+            ));
+        }
+        Ok(table)
+    }
+
+    pub fn print_all_table(&self) {
+        //Intended for debbuging
+        match self.mode {
+            HashMode::Extendible => {
+                println!("Global depth: {}", self.global_depth);
+                for (index, page) in self.directory.iter().enumerate() {
+                    println!(
+                        "Directory {:0width$b} -> Bucket {}",
+                        index,
+                        page,
+                        width = self.global_depth as usize
+                    );
+                }
+            }
+            HashMode::Linear => println!(
+                "Level: {}, split pointer: {}",
+                self.level, self.split_pointer
+            ),
+        }
+
+        let mut file = match self.open_file() {
             Ok(file) => file,
             Err(_) => return,
         };
+        for page in self.bucket_pages() {
+            let chain = match self.read_chain(&mut file, page) {
+                Ok(chain) => chain,
+                Err(_) => return,
+            };
+            for (page, bucket) in chain {
+                println!(
+                    "Bucket: {} (local depth {}, next {}) [  ",
+                    page, bucket.local_depth, bucket.next as i64
+                );
+                for item in bucket.items.iter().flatten() {
+                    println!(
+                        "Key: {}, Value: {}",
+                        item.key.to_display(),
+                        item.value.to_display()
+                    );
+                }
+                println!("  ]");
+            }
+        }
+    }
+
+    //Keys are unique, inserting one that is already stored fails with DuplicateKey
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let mut file = self.open_file()?;
+        self.insert_item(&mut file, Item { key, value })?;
+        self.size += 1;
+        self.write_header(&mut file)
+    }
+
+    //Inserts everything with the file opened once and the header written only at the end,
+    //stops at the first error. Returns how many items were inserted
+    pub fn bulk_insert<I>(&mut self, items: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        let mut file = self.open_file()?;
+        let mut inserted = 0;
+        let mut result = Ok(());
+        for item in items {
+            result = item.and_then(|(key, value)| self.insert_item(&mut file, Item { key, value }));
+            if result.is_err() {
+                break;
+            }
+            inserted += 1;
+        }
+        self.size += inserted;
+        self.write_header(&mut file)?;
+        result.map(|_| inserted)
+    }
 
-        let mut buffer = [0u8; 100 * ITEMS_PER_PAGE];
-        let mut offset = 0;
-        let mut bucket = 0;
+    fn insert_item(&mut self, file: &mut File, item: Item<K, V>) -> Result<()> {
+        let key = item.key;
+        let max_overflow_pages = self.split_policy.max_overflow_pages();
         loop {
-            match file.seek(SeekFrom::Start(offset)) {
-                Ok(_) => {
-                    match file.read_exact(&mut buffer) {
-                        Ok(_) => {
-                            for chunk in buffer.chunks_exact(100) {
-                                print!("Bucket: {} [  ", bucket);
-                                let (key_buf, value_buf) =
-                                    chunk.split_at(std::mem::size_of::<u32>());
-                                let registro_key = u32::from_be_bytes(key_buf.try_into().unwrap());
-                                let registro_value = value_buf
-                                    .iter()
-                                    .map(|&c| c as char)
-                                    .collect::<String>()
-                                    .into_bytes();
-                                println!(
-                                    "Key: {}, Value: {}",
-                                    registro_key,
-                                    String::from_utf8(registro_value).unwrap()
-                                );
-                            }
-                            println!("  ]");
-                            bucket += 1;
-                        }
-                        Err(_) => return,
+            let first_page = self.directory[self.hash(&key) as usize];
+            let chain = self.read_chain(file, first_page)?;
+            if chain
+                .iter()
+                .any(|(_, bucket)| bucket.position(&key).is_some())
+            {
+                return Err(Error::DuplicateKey(key.to_display()));
+            }
+            for (page, mut bucket) in chain.iter().cloned() {
+                if let Some(slot) = bucket.free_slot() {
+                    bucket.put(slot, item);
+                    return self.write_bucket(file, page, &bucket);
+                }
+            }
+
+            let overflow_pages = chain.len() - 1;
+            match self.mode {
+                //The whole chain is full, only this bucket gets split and then we try again
+                HashMode::Extendible => {
+                    if overflow_pages < max_overflow_pages || !self.can_split(&chain, &key) {
+                        return self.append_overflow_page(file, &chain, item);
                     }
+                    self.split_bucket(file, first_page)?;
+                }
+                //The item goes to a new overflow page and, once the chain is too long,
+                //the bucket at the split pointer is split
+                HashMode::Linear => {
+                    self.append_overflow_page(file, &chain, item)?;
+                    if overflow_pages + 1 > max_overflow_pages {
+                        return self.split_next(file);
+                    }
+                    return Ok(());
                 }
-                Err(_) => return,
             }
-            offset += 100 * ITEMS_PER_PAGE as u64;
         }
     }
 
-    pub fn insert(&mut self, key: u32, value: [char; 96]) -> Result<(), &'static str> {
-        let mut file = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(self.file_name.clone())
-        {
-            Ok(file) => file,
-            Err(_) => return Err("Error opening file"),
-        };
-        let mut buffer = [0u8; 100 * ITEMS_PER_PAGE];
-        let mut offset = self.hash(key) * 100 * ITEMS_PER_PAGE as u64;
-        match file.seek(SeekFrom::Start(offset)) {
-            Ok(_) => {
-                match file.read(&mut buffer) {
-                    Ok(_) => {
-                        for buffer in buffer.chunks(100) {
-                            let (key_buf, _) = buffer.split_at(std::mem::size_of::<u32>());
-                            let registro_key = u32::from_be_bytes(key_buf.try_into().unwrap());
-                            if registro_key == EMPTY_ITEM_KEY {
-                                let mut insert_buffer = key.to_be_bytes().to_vec();
-                                insert_buffer
-                                    .append(&mut value.iter().collect::<String>().into_bytes());
-                                file.seek(SeekFrom::Start(offset)).unwrap();
-                                match file.write_all(&insert_buffer) {
-                                    Ok(_) => {
-                                        return Ok(());
-                                    }
-                                    Err(_) => {
-                                        return Err("Error writing key value pair to file");
-                                    }
-                                }
-                            }
-                            offset += 100;
-                        }
-                        match self.resize_and_insert(key, value) {
-                            Ok(_) => return Ok(()),
-                            Err(_) => return Err("Error resizing and inserting"),
-                        }
-                    }
-                    Err(_) => return Err("Error reading file"),
+    pub fn read_key_value(&self, key: K) -> Result<Item<K, V>> {
+        //Busca um registro pelo sua chave
+        let mut file = self.open_file()?;
+        let chain = self.read_chain(&mut file, self.directory[self.hash(&key) as usize])?;
+        for (_, bucket) in chain {
+            if let Some(slot) = bucket.position(&key) {
+                return Ok(bucket.items[slot].unwrap());
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    //Replaces the value of a key that is already stored, in place
+    pub fn update_value(&mut self, key: K, value: V) -> Result<()> {
+        let mut file = self.open_file()?;
+        let chain = self.read_chain(&mut file, self.directory[self.hash(&key) as usize])?;
+        for (page, mut bucket) in chain {
+            if let Some(slot) = bucket.position(&key) {
+                bucket.put(slot, Item { key, value });
+                return self.write_bucket(&mut file, page, &bucket);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    //After the removal buddy buckets may merge, and the file is compacted so it shrinks
+    //together with the table
+    pub fn remove_key_value(&mut self, key: K) -> Result<()> {
+        let mut file = self.open_file()?;
+        let first_page = self.directory[self.hash(&key) as usize];
+        let mut chain = self.read_chain(&mut file, first_page)?;
+        let (i, slot) = chain
+            .iter()
+            .enumerate()
+            .find_map(|(i, (_, bucket))| bucket.position(&key).map(|slot| (i, slot)))
+            .ok_or(Error::NotFound)?;
+        chain[i].1.take(slot);
+
+        let items: Vec<_> = chain
+            .iter()
+            .flat_map(|(_, bucket)| bucket.items.iter().flatten().copied())
+            .collect();
+        if chain.len() > items.len().div_ceil(Bucket::<K, V>::SLOTS).max(1) {
+            //The items fit in fewer pages, the chain is packed and the spare pages are freed
+            let (bucket, local_depth) = (chain[0].1.bucket, chain[0].1.local_depth);
+            let pages = chain.iter().map(|(page, _)| *page).collect();
+            self.write_chain(&mut file, pages, bucket, local_depth, &items)?;
+        } else {
+            self.write_bucket(&mut file, chain[i].0, &chain[i].1)?;
+        }
+        self.size = self.size.saturating_sub(1);
+
+        if self.merge_threshold > 0 {
+            match self.mode {
+                HashMode::Extendible => self.merge_buddies(&mut file, first_page)?,
+                HashMode::Linear => self.merge_last(&mut file)?,
+            }
+        }
+        self.compact_file(&mut file)?;
+        self.write_header(&mut file)
+    }
+
+    fn fits_merged(&self, chain: &[(u64, Bucket<K, V>)], buddy: &[(u64, Bucket<K, V>)]) -> bool {
+        let items: usize = chain
+            .iter()
+            .chain(buddy)
+            .map(|(_, bucket)| bucket.count)
+            .sum();
+        items * 100 <= Bucket::<K, V>::SLOTS * self.merge_threshold as usize
+    }
+
+    //Undoes extendible splits: the bucket and its buddy (same local depth, differing only in
+    //the highest bit of it) become one bucket again, then the directory is halved while its
+    //two halves point to the same buckets. Never goes below the initial capacity
+    fn merge_buddies(&mut self, file: &mut File, mut page: u64) -> Result<()> {
+        let initial_depth = self.initial_capacity.trailing_zeros();
+        loop {
+            let chain = self.read_chain(file, page)?;
+            let (bucket, local_depth) = (chain[0].1.bucket, chain[0].1.local_depth);
+            if local_depth <= initial_depth {
+                break;
+            }
+            let high_bit = 1 << (local_depth - 1);
+            let buddy_page = self.directory[(bucket ^ high_bit) as usize];
+            let buddy = self.read_chain(file, buddy_page)?;
+            if buddy[0].1.local_depth != local_depth || !self.fits_merged(&chain, &buddy) {
+                break;
+            }
+
+            let (low, high) = match bucket & high_bit {
+                0 => (chain, buddy),
+                _ => (buddy, chain),
+            };
+            let items: Vec<_> = low
+                .iter()
+                .chain(&high)
+                .flat_map(|(_, bucket)| bucket.items.iter().flatten().copied())
+                .collect();
+            page = low[0].0;
+            let low_pages = low.iter().map(|(page, _)| *page).collect();
+            self.write_chain(file, low_pages, bucket & !high_bit, local_depth - 1, &items)?;
+            for (high_page, _) in &high {
+                self.release_page(file, *high_page)?;
+            }
+            for entry in self.directory.iter_mut() {
+                if *entry == high[0].0 {
+                    *entry = page;
                 }
             }
-            Err(_) => return Err("Error seeking file"),
+            self.capacity -= 1;
+            self.merges += 1;
+        }
+
+        while self.global_depth > initial_depth {
+            let half = self.directory.len() / 2;
+            if self.directory[..half] != self.directory[half..] {
+                break;
+            }
+            self.directory.truncate(half);
+            self.global_depth -= 1;
+            self.resizes += 1;
         }
+        Ok(())
     }
 
-    pub fn read_key_value(&self, key: u32) -> Result<Item, &'static str> { //Busca um registro pelo sua chave
-        let mut file = match OpenOptions::new().read(true).open(self.file_name.clone()) {
-            Ok(file) => file,
-            Err(_) => return Err("Error opening file"),
-        };
-        let mut buffer = [0u8; 100 * ITEMS_PER_PAGE];
-        let offset = self.hash(key) * 100 * ITEMS_PER_PAGE as u64;
-        match file.seek(SeekFrom::Start(offset)) {
-            Ok(_) => {
-                match file.read_exact(&mut buffer) {
-                    Ok(_) => {
-                        for chunk in buffer.chunks_exact(100) {
-                            let (key_buf, value_buf) = chunk.split_at(std::mem::size_of::<u32>());
-                            let registro_key = u32::from_be_bytes(key_buf.try_into().unwrap());
-                            if registro_key == key {
-                                let value_buf = String::from_utf8(
-                                    value_buf
-                                        .iter()
-                                        .map(|&c| c as char)
-                                        .collect::<String>()
-                                        .into_bytes(),
-                                )
-                                .unwrap();
-                                let item = Item {
-                                    key: registro_key,
-                                    value: value_buf
-                                        .chars()
-                                        .take(96)
-                                        .collect::<Vec<char>>()
-                                        .try_into()
-                                        .unwrap(),
-                                };
-                                file.rewind().unwrap();
-                                return Ok(item);
-                            }
-                        }
+    //Undoes linear splits: the last bucket goes back into the bucket it was split from,
+    //moving the split pointer (and the level) backwards. Never goes below the initial capacity
+    fn merge_last(&mut self, file: &mut File) -> Result<()> {
+        while self.capacity > self.initial_capacity {
+            let (level, split_pointer) = match self.split_pointer {
+                0 => (
+                    self.level - 1,
+                    (self.initial_capacity << (self.level - 1)) - 1,
+                ),
+                split_pointer => (self.level, split_pointer - 1),
+            };
+            let last = self.read_chain(file, self.directory[self.capacity - 1])?;
+            let chain = self.read_chain(file, self.directory[split_pointer])?;
+            if !self.fits_merged(&chain, &last) {
+                break;
+            }
+
+            let items: Vec<_> = chain
+                .iter()
+                .chain(&last)
+                .flat_map(|(_, bucket)| bucket.items.iter().flatten().copied())
+                .collect();
+            let pages = chain.iter().map(|(page, _)| *page).collect();
+            self.write_chain(file, pages, split_pointer as u32, 0, &items)?;
+            for (page, _) in &last {
+                self.release_page(file, *page)?;
+            }
+            self.directory.pop();
+            self.capacity -= 1;
+            self.merges += 1;
+            if level != self.level {
+                self.resizes += 1;
+            }
+            self.level = level;
+            self.split_pointer = split_pointer;
+        }
+        Ok(())
+    }
+
+    //Moves the pages at the end of the file into the free pages before them and truncates
+    //the file after the last page in use
+    fn compact_file(&mut self, file: &mut File) -> Result<()> {
+        self.free_pages.sort_unstable_by(|a, b| b.cmp(a));
+        loop {
+            while self.free_pages.first() == Some(&(self.page_count - 1)) {
+                self.free_pages.remove(0);
+                self.page_count -= 1;
+            }
+            let target = match self.free_pages.pop() {
+                Some(target) => target,
+                None => break,
+            };
+            let last = self.page_count - 1;
+            self.move_page(file, last, target)?;
+            self.page_count -= 1;
+        }
+        file.set_len(self.page_count * PAGE_SIZE as u64)?;
+        Ok(())
+    }
+
+    //Copies a bucket page to another place in the file and points its directory entries or
+    //the previous page of its overflow chain to the new place
+    fn move_page(&mut self, file: &mut File, from: u64, to: u64) -> Result<()> {
+        let bucket = self.read_bucket(file, from)?;
+        match bucket.kind {
+            PageKind::Primary => {
+                for entry in self.directory.iter_mut() {
+                    if *entry == from {
+                        *entry = to;
                     }
-                    Err(_) => return Err("Error reading file"),
                 }
             }
-            Err(_) => return Err("Error seeking file"),
+            PageKind::Overflow => {
+                let chain = self.read_chain(file, self.directory[bucket.bucket as usize])?;
+                let (previous_page, mut previous) = chain
+                    .into_iter()
+                    .find(|(_, previous)| previous.next == from)
+                    .ok_or(Error::CorruptPage {
+                        page: from,
+                        reason: "overflow page is not in the chain of its bucket",
+                    })?;
+                previous.next = to;
+                self.write_bucket(file, previous_page, &previous)?;
+            }
+            PageKind::Free => {
+                return Err(Error::CorruptPage {
+                    page: from,
+                    reason: "free page was not in the free list",
+                })
+            }
+        }
+        self.write_bucket(file, to, &bucket)
+    }
+
+    //Chains a new overflow page holding only the given item to the end of the bucket
+    fn append_overflow_page(
+        &mut self,
+        file: &mut File,
+        chain: &[(u64, Bucket<K, V>)],
+        item: Item<K, V>,
+    ) -> Result<()> {
+        let (last_page, mut last_bucket) = chain.last().cloned().unwrap();
+        let new_page = self.allocate_page();
+        let mut overflow = Bucket::empty(
+            PageKind::Overflow,
+            last_bucket.bucket,
+            last_bucket.local_depth,
+        );
+        overflow.put(0, item);
+        self.write_bucket(file, new_page, &overflow)?;
+        last_bucket.next = new_page;
+        self.write_bucket(file, last_page, &last_bucket)
+    }
+
+    //Splitting (maybe a few times in a row) only helps if some item differs from the new key
+    //in a bit the directory may still use, otherwise (e.g. the same key inserted many times)
+    //the directory would double for nothing. It may use bits up to max_directory_depth, or up
+    //to the global depth when that is deeper
+    fn can_split(&self, chain: &[(u64, Bucket<K, V>)], key: &K) -> bool {
+        let local_depth = chain[0].1.local_depth;
+        let usable_depth = self.max_directory_depth.max(self.global_depth);
+        if local_depth >= usable_depth {
+            return false;
+        }
+        let usable_bits = ((1u64 << usable_depth) - 1) & !((1u64 << local_depth) - 1);
+        let key_hash = self.hash_function.hash(key);
+        chain
+            .iter()
+            .flat_map(|(_, bucket)| bucket.items.iter().flatten())
+            .any(|item| (self.hash_function.hash(&item.key) ^ key_hash) & usable_bits != 0)
+    }
+
+    //Splits only the overflowing bucket, the directory doubles by copying its pointers
+    //when the bucket already uses every bit of the global depth
+    fn split_bucket(&mut self, file: &mut File, page: u64) -> Result<()> {
+        let chain = self.read_chain(file, page)?;
+        let local_depth = chain[0].1.local_depth;
+        let bucket = chain[0].1.bucket;
+        if local_depth == self.global_depth {
+            if self.global_depth == MAX_GLOBAL_DEPTH {
+                return Err(Error::Full("bucket cannot be split any further"));
+            }
+            self.directory.extend_from_within(..);
+            self.global_depth += 1;
+            self.resizes += 1;
+        }
+
+        //The bit right above the old local depth decides where each item goes
+        let (old_items, new_items): (Vec<_>, Vec<_>) = chain
+            .iter()
+            .flat_map(|(_, bucket)| bucket.items.iter().flatten().copied())
+            .partition(|item| (self.hash_function.hash(&item.key) >> local_depth) & 1 == 0);
+
+        let new_page = self.allocate_page();
+        for (index, entry) in self.directory.iter_mut().enumerate() {
+            if *entry == page && (index >> local_depth) & 1 == 1 {
+                *entry = new_page;
+            }
+        }
+
+        let old_pages = chain.iter().map(|(page, _)| *page).collect();
+        self.write_chain(file, old_pages, bucket, local_depth + 1, &old_items)?;
+        let new_bucket = bucket | (1 << local_depth);
+        self.write_chain(
+            file,
+            vec![new_page],
+            new_bucket,
+            local_depth + 1,
+            &new_items,
+        )?;
+        self.capacity += 1;
+        self.splits += 1;
+        Ok(())
+    }
+
+    //Linear hashing split: the bucket at the split pointer is rehashed with the next level,
+    //its items either stay or move to the new bucket appended at the end of the directory
+    fn split_next(&mut self, file: &mut File) -> Result<()> {
+        let old_page = self.directory[self.split_pointer];
+        let chain = self.read_chain(file, old_page)?;
+        let next_modulus = (self.initial_capacity as u64) << (self.level + 1);
+        let (old_items, new_items): (Vec<_>, Vec<_>) = chain
+            .iter()
+            .flat_map(|(_, bucket)| bucket.items.iter().flatten().copied())
+            .partition(|item| {
+                self.hash_function.hash(&item.key) % next_modulus == self.split_pointer as u64
+            });
+
+        let new_page = self.allocate_page();
+        let new_bucket = self.directory.len() as u32;
+        self.directory.push(new_page);
+        let old_pages = chain.iter().map(|(page, _)| *page).collect();
+        self.write_chain(file, old_pages, self.split_pointer as u32, 0, &old_items)?;
+        self.write_chain(file, vec![new_page], new_bucket, 0, &new_items)?;
+        self.capacity += 1;
+        self.splits += 1;
+
+        self.split_pointer += 1;
+        if self.split_pointer == self.initial_capacity << self.level {
+            //Every bucket of this round was split, the next round starts from the beginning
+            self.level += 1;
+            self.resizes += 1;
+            self.split_pointer = 0;
         }
-        file.rewind().unwrap();
-        Err("Error reading key")
+        Ok(())
+    }
+
+    //Every item, bucket by bucket, reading one page at a time
+    pub fn iter(&self) -> Result<Iter<'_, K, V>> {
+        Ok(Iter {
+            entries: self.entries()?,
+        })
     }
 
-    pub fn remove_key_value(&mut self, key: u32) -> Result<(), &'static str> {
-        let mut file = match OpenOptions::new().read(true).write(true).open(self.file_name.clone()) {
+    //Like iter, also telling where each item is stored
+    pub fn entries(&self) -> Result<Entries<'_, K, V>> {
+        Ok(Entries {
+            table: self,
+            file: self.open_file()?,
+            primary_pages: self.bucket_pages().into_iter(),
+            page: None,
+            slot: 0,
+            chain_length: 0,
+            done: false,
+        })
+    }
+
+    pub fn read_all_table(&mut self) -> Vec<Vec<Item<K, V>>> {
+        let mut new_table: Vec<Vec<Item<K, V>>> = Vec::new();
+        let mut file = match self.open_file() {
             Ok(file) => file,
-            Err(_) => return Err("Error opening file"),
+            Err(_) => return new_table,
         };
-        let mut buffer = [0u8; 100 * ITEMS_PER_PAGE];
-        let mut offset = self.hash(key) * 100 * ITEMS_PER_PAGE as u64;
-        match file.seek(SeekFrom::Start(offset)) {
-            Ok(_) => {
-                match file.read(&mut buffer) {
-                    Ok(_) => {
-                        for buffer in buffer.chunks(100) {
-                            let (key_buf, _) = buffer.split_at(std::mem::size_of::<u32>());
-                            let registro_key = u32::from_be_bytes(key_buf.try_into().unwrap());
-                            if registro_key == key {
-                                let mut insert_buffer = EMPTY_ITEM_KEY.to_be_bytes().to_vec();
-                                insert_buffer
-                                    .append(&mut EMPTY_ITEM_VALUE.iter().collect::<String>().into_bytes());
-                                file.seek(SeekFrom::Start(offset)).unwrap();
-                                match file.write_all(&insert_buffer) {
-                                    Ok(_) => {
-                                        return Ok(());
-                                    }
-                                    Err(_) => {
-                                        return Err("Error removing key value pair");
-                                    }
-                                }
-                            }
-                            offset += 100;
-                        }
-                        Ok(())
-                    }
-                    Err(_) => return Err("Error reading file"),
-                }
+        for page in self.bucket_pages() {
+            match self.read_chain(&mut file, page) {
+                Ok(chain) => new_table.extend(
+                    chain
+                        .into_iter()
+                        .map(|(_, bucket)| bucket.items.into_iter().flatten().collect()),
+                ),
+                Err(_) => return new_table,
+            }
+        }
+        new_table
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    //Number of buckets
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    //Reads every bucket chain once to count its items and pages
+    pub fn stats(&self) -> Result<TableStats> {
+        let mut file = self.open_file()?;
+        let mut occupancy = Vec::new();
+        let mut longest_chain = 0;
+        let mut overflow_pages = 0;
+        for page in self.bucket_pages() {
+            let chain = self.read_chain(&mut file, page)?;
+            let items: usize = chain.iter().map(|(_, bucket)| bucket.count).sum();
+            if occupancy.len() <= items {
+                occupancy.resize(items + 1, 0);
             }
-            Err(_) => return Err("Error seeking file"),
+            occupancy[items] += 1;
+            longest_chain = longest_chain.max(chain.len());
+            overflow_pages += chain.len() - 1;
         }
+        Ok(TableStats {
+            items: self.size,
+            buckets: self.capacity,
+            slots_per_page: Bucket::<K, V>::SLOTS,
+            occupancy,
+            longest_chain,
+            overflow_pages,
+            free_pages: self.free_pages.len(),
+            splits: self.splits,
+            merges: self.merges,
+            resizes: self.resizes,
+            file_size: file.metadata()?.len(),
+        })
     }
 
-    fn resize_and_insert(&mut self, key: u32, value: [char; 96]) -> Result<(), &'static str> {
-        let old_table = self.read_all_table();
-        self.capacity = self.capacity * 2;
-        //now new_table with double the size of the old
-        let mut new_table = vec![
-            [Item {
-                key: EMPTY_ITEM_KEY,
-                value: EMPTY_ITEM_VALUE,
-            }; ITEMS_PER_PAGE * 2];
-            self.capacity
-        ];
+    pub fn get_mode(&self) -> HashMode {
+        self.mode
+    }
+
+    pub fn get_split_policy(&self) -> SplitPolicy {
+        self.split_policy
+    }
+
+    pub fn get_hash_function(&self) -> HashFunction {
+        self.hash_function
+    }
+
+    pub fn set_split_policy(&mut self, split_policy: SplitPolicy) -> Result<()> {
+        self.split_policy = split_policy;
+        let mut file = self.open_file()?;
+        self.write_header(&mut file)
+    }
+
+    pub fn get_merge_threshold(&self) -> u32 {
+        self.merge_threshold
+    }
+
+    //Percent of the slots of a page, 0 turns merging off
+    pub fn set_merge_threshold(&mut self, merge_threshold: u32) -> Result<()> {
+        self.merge_threshold = merge_threshold.min(100);
+        let mut file = self.open_file()?;
+        self.write_header(&mut file)
+    }
 
-        for i in 0..old_table.len() {
-            for j in 0..ITEMS_PER_PAGE {
-                if old_table[i][j].key != EMPTY_ITEM_KEY {
-                    let new_index = self.hash(old_table[i][j].key);
-                    new_table[new_index as usize][j] = old_table[i][j];
+    pub fn get_max_directory_depth(&self) -> u32 {
+        self.max_directory_depth
+    }
+
+    //Extendible mode only. A full bucket that already uses every bit of a directory this deep
+    //grows an overflow chain instead of doubling it again, so keys whose hashes only differ in
+    //high bits cannot make the directory huge. A deeper directory is kept as it is
+    pub fn set_max_directory_depth(&mut self, max_directory_depth: u32) -> Result<()> {
+        self.max_directory_depth = max_directory_depth.min(MAX_GLOBAL_DEPTH);
+        let mut file = self.open_file()?;
+        self.write_header(&mut file)
+    }
+
+    pub fn get_global_depth(&self) -> u32 {
+        self.global_depth
+    }
+
+    pub fn get_level(&self) -> u32 {
+        self.level
+    }
+
+    pub fn get_split_pointer(&self) -> usize {
+        self.split_pointer
+    }
+
+    //Index in the directory
+    fn hash(&self, key: &K) -> u64 {
+        let code = self.hash_function.hash(key);
+        match self.mode {
+            //The lowest global_depth bits of the hash
+            HashMode::Extendible => code & ((1u64 << self.global_depth) - 1),
+            HashMode::Linear => {
+                let bucket = code % ((self.initial_capacity as u64) << self.level);
+                if bucket < self.split_pointer as u64 {
+                    //Already split in this round, so the next level decides
+                    code % ((self.initial_capacity as u64) << (self.level + 1))
                 } else {
-                    continue;
+                    bucket
                 }
             }
         }
-        //now we need to insert the new item
-        let new_index = self.hash(key);
-        for i in 0..ITEMS_PER_PAGE {
-            if new_table[new_index as usize][i].key == EMPTY_ITEM_KEY {
-                new_table[new_index as usize][i] = Item { key, value };
-                break;
+    }
+
+    //Primary page of every bucket, each one only once and in file order
+    fn bucket_pages(&self) -> Vec<u64> {
+        let mut pages = self.directory.clone();
+        pages.sort_unstable();
+        pages.dedup();
+        pages
+    }
+
+    fn allocate_page(&mut self) -> u64 {
+        match self.free_pages.pop() {
+            Some(page) => page,
+            None => {
+                self.page_count += 1;
+                self.page_count - 1
             }
         }
+    }
 
-        let mut file = match OpenOptions::new()
+    fn open_file(&self) -> Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
             .write(true)
-            .open(self.file_name.clone())
-        {
-            Ok(file) => file,
-            Err(_) => return Err("Error opening file"),
-        };
-        file.rewind().unwrap();
-        let mut buffer = Vec::new();
-        for i in 0..new_table.len() {
-            for j in 0..ITEMS_PER_PAGE {
-                buffer.append(&mut new_table[i][j].key.to_be_bytes().to_vec());
-                buffer.append(
-                    &mut new_table[i][j]
-                        .value
-                        .iter()
-                        .collect::<String>()
-                        .into_bytes(),
-                );
+            .open(&self.file_name)?)
+    }
+
+    //Reads the primary page and every overflow page chained from it
+    fn read_chain(&self, file: &mut File, first_page: u64) -> Result<Vec<(u64, Bucket<K, V>)>> {
+        let mut chain = Vec::new();
+        let mut page = first_page;
+        while page != NO_PAGE {
+            //A chain can never be longer than the file, so a longer one has a cycle
+            if page >= self.page_count || chain.len() as u64 >= self.page_count {
+                return Err(Error::CorruptPage {
+                    page: chain.last().map_or(first_page, |(page, _)| *page),
+                    reason: "broken overflow chain",
+                });
             }
+            let bucket = self.read_bucket(file, page)?;
+            let next = bucket.next;
+            chain.push((page, bucket));
+            page = next;
+        }
+        Ok(chain)
+    }
+
+    //Rewrites a chain with the given items packed from the first page, allocating overflow
+    //pages when the existing ones are not enough and releasing the ones left unused
+    fn write_chain(
+        &mut self,
+        file: &mut File,
+        mut pages: Vec<u64>,
+        bucket_number: u32,
+        local_depth: u32,
+        items: &[Item<K, V>],
+    ) -> Result<()> {
+        let needed = items.len().div_ceil(Bucket::<K, V>::SLOTS).max(1);
+        while pages.len() < needed {
+            let page = self.allocate_page();
+            pages.push(page);
+        }
+        for page in pages.drain(needed..) {
+            self.release_page(file, page)?;
         }
-        match file.write_all(&buffer) {
-            Ok(_) => {
-                return Ok(());
+
+        for (i, &page) in pages.iter().enumerate() {
+            let kind = if i == 0 {
+                PageKind::Primary
+            } else {
+                PageKind::Overflow
+            };
+            let mut bucket = Bucket::empty(kind, bucket_number, local_depth);
+            bucket.next = pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            for (slot, item) in items
+                .iter()
+                .skip(i * Bucket::<K, V>::SLOTS)
+                .take(Bucket::<K, V>::SLOTS)
+                .enumerate()
+            {
+                bucket.put(slot, *item);
             }
-            Err(_) => return Err("Error writing key value pair to file"),
+            self.write_bucket(file, page, &bucket)?;
         }
+        Ok(())
     }
 
-    pub fn read_all_table(&mut self) -> Vec<[Item; ITEMS_PER_PAGE]> {
-        let mut new_table: Vec<[Item; ITEMS_PER_PAGE]> = Vec::new();
-        let mut new_table_item: [Item; ITEMS_PER_PAGE] = [Item {
-            key: EMPTY_ITEM_KEY,
-            value: EMPTY_ITEM_VALUE,
-        }; ITEMS_PER_PAGE];
-        let mut file = match OpenOptions::new().read(true).open(self.file_name.clone()) {
-            Ok(file) => file,
-            Err(_) => return new_table,
+    //Marks the page as free on disk so a reopened table can find it again
+    fn release_page(&mut self, file: &mut File, page: u64) -> Result<()> {
+        self.write_bucket(file, page, &Bucket::<K, V>::empty(PageKind::Free, 0, 0))?;
+        self.free_pages.push(page);
+        Ok(())
+    }
+
+    fn read_bucket(&self, file: &mut File, page: u64) -> Result<Bucket<K, V>> {
+        let mut buffer = [0u8; PAGE_SIZE];
+        file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        file.read_exact(&mut buffer)?;
+        Bucket::from_bytes(page, &buffer)
+    }
+
+    fn write_bucket(&self, file: &mut File, page: u64, bucket: &Bucket<K, V>) -> Result<()> {
+        //A page at the end of the file is only partly written, so the file grows to hold all of it
+        let page_end = (page + 1) * PAGE_SIZE as u64;
+        if file.metadata()?.len() < page_end {
+            file.set_len(page_end)?;
+        }
+        file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        file.write_all(&bucket.to_bytes())?;
+        Ok(())
+    }
+
+    //Header page: magic, format version, page layout, hash parameters and counters
+    fn header_bytes(&self) -> Vec<u8> {
+        let (policy_tag, policy_length) = self.split_policy.to_header();
+        let (function_tag, seed) = self.hash_function.to_header();
+        let mut buffer = Vec::with_capacity(PAGE_SIZE);
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+        buffer.extend_from_slice(&(Bucket::<K, V>::SLOTS as u32).to_be_bytes());
+        buffer.extend_from_slice(&((K::WIDTH + V::WIDTH) as u32).to_be_bytes());
+        buffer.push(match self.mode {
+            HashMode::Extendible => 0,
+            HashMode::Linear => 1,
+        });
+        buffer.push(policy_tag);
+        buffer.extend_from_slice(&policy_length.to_be_bytes());
+        buffer.extend_from_slice(&(self.size as u64).to_be_bytes());
+        buffer.extend_from_slice(&(self.capacity as u64).to_be_bytes());
+        buffer.extend_from_slice(&self.global_depth.to_be_bytes());
+        buffer.extend_from_slice(&(self.initial_capacity as u64).to_be_bytes());
+        buffer.extend_from_slice(&self.level.to_be_bytes());
+        buffer.extend_from_slice(&(self.split_pointer as u64).to_be_bytes());
+        buffer.extend_from_slice(&self.page_count.to_be_bytes());
+        buffer.extend_from_slice(&(K::WIDTH as u32).to_be_bytes());
+        buffer.push(function_tag);
+        buffer.extend_from_slice(&seed.to_be_bytes());
+        buffer.extend_from_slice(&self.merge_threshold.to_be_bytes());
+        buffer.extend_from_slice(&self.splits.to_be_bytes());
+        buffer.extend_from_slice(&self.merges.to_be_bytes());
+        buffer.extend_from_slice(&self.resizes.to_be_bytes());
+        buffer.extend_from_slice(&self.max_directory_depth.to_be_bytes());
+        buffer.resize(PAGE_SIZE, 0);
+        buffer
+    }
+
+    fn write_header(&self, file: &mut File) -> Result<()> {
+        file.seek(SeekFrom::Start(HEADER_PAGE * PAGE_SIZE as u64))?;
+        file.write_all(&self.header_bytes())?;
+        Ok(())
+    }
+}
+
+//Snapshot of how full the table is, see DynamicHashTable::stats
+#[derive(Clone, Debug)]
+pub struct TableStats {
+    items: usize,
+    buckets: usize,
+    slots_per_page: usize,
+    occupancy: Vec<usize>, // occupancy[n] = buckets holding n items, counting their overflow pages
+    longest_chain: usize,  // In pages, 1 when no bucket has overflow pages
+    overflow_pages: usize,
+    free_pages: usize, // Released by splits, reused or truncated away later
+    splits: u64,
+    merges: u64,
+    resizes: u64,
+    file_size: u64,
+}
+
+#[allow(dead_code)]
+impl TableStats {
+    pub fn get_items(&self) -> usize {
+        self.items
+    }
+
+    pub fn get_buckets(&self) -> usize {
+        self.buckets
+    }
+
+    //Items that fit in the primary pages of every bucket
+    pub fn get_capacity(&self) -> usize {
+        self.buckets * self.slots_per_page
+    }
+
+    pub fn get_load_factor(&self) -> f64 {
+        self.items as f64 / self.get_capacity() as f64
+    }
+
+    pub fn get_occupancy(&self) -> &[usize] {
+        &self.occupancy
+    }
+
+    pub fn get_longest_chain(&self) -> usize {
+        self.longest_chain
+    }
+
+    pub fn get_overflow_pages(&self) -> usize {
+        self.overflow_pages
+    }
+
+    pub fn get_free_pages(&self) -> usize {
+        self.free_pages
+    }
+
+    pub fn get_splits(&self) -> u64 {
+        self.splits
+    }
+
+    pub fn get_merges(&self) -> u64 {
+        self.merges
+    }
+
+    pub fn get_resizes(&self) -> u64 {
+        self.resizes
+    }
+
+    pub fn get_file_size(&self) -> u64 {
+        self.file_size
+    }
+}
+
+impl fmt::Display for TableStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Items: {}, buckets: {}, capacity: {}, load factor: {:.2}",
+            self.items,
+            self.buckets,
+            self.get_capacity(),
+            self.get_load_factor()
+        )?;
+        writeln!(
+            f,
+            "Longest chain: {} pages, overflow pages: {}, free pages: {}, file size: {} bytes",
+            self.longest_chain, self.overflow_pages, self.free_pages, self.file_size
+        )?;
+        writeln!(
+            f,
+            "Splits: {}, merges: {}, resizes: {}",
+            self.splits, self.merges, self.resizes
+        )?;
+        for (items, buckets) in self.occupancy.iter().enumerate() {
+            if *buckets > 0 {
+                writeln!(f, "{:>4} items: {} buckets", items, buckets)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//An item and its place in the file: the bucket number (low bits of the hash in extendible
+//mode), the page of the bucket chain and the slot inside that page
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<K, V> {
+    bucket: u32,
+    page: u64,
+    slot: usize,
+    item: Item<K, V>,
+}
+
+#[allow(dead_code)]
+impl<K: HashKey, V: FixedWidth> Entry<K, V> {
+    pub fn get_bucket(&self) -> u32 {
+        self.bucket
+    }
+
+    pub fn get_page(&self) -> u64 {
+        self.page
+    }
+
+    pub fn get_slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn get_item(&self) -> Item<K, V> {
+        self.item
+    }
+}
+
+//Walks the bucket chains in file order of their primary pages, only the current page is kept
+//in memory. Stops after the first error
+pub struct Entries<'a, K, V> {
+    table: &'a DynamicHashTable<K, V>,
+    file: File,
+    primary_pages: std::vec::IntoIter<u64>,
+    page: Option<(u64, Bucket<K, V>)>,
+    slot: usize,
+    chain_length: u64, // Pages read from the current chain, to stop at a cycle
+    done: bool,
+}
+
+impl<K: HashKey, V: FixedWidth> Entries<'_, K, V> {
+    //The next page of the current chain, or the primary page of the next bucket
+    fn next_page(&mut self) -> Result<Option<(u64, Bucket<K, V>)>> {
+        let next = match &self.page {
+            Some((_, bucket)) if bucket.next != NO_PAGE => bucket.next,
+            _ => match self.primary_pages.next() {
+                Some(page) => {
+                    self.chain_length = 0;
+                    page
+                }
+                None => return Ok(None),
+            },
         };
+        self.chain_length += 1;
+        if next >= self.table.page_count || self.chain_length > self.table.page_count {
+            return Err(Error::CorruptPage {
+                page: next,
+                reason: "broken overflow chain",
+            });
+        }
+        let bucket = self.table.read_bucket(&mut self.file, next)?;
+        Ok(Some((next, bucket)))
+    }
+}
 
-        let mut buffer = [0u8; 100 * ITEMS_PER_PAGE];
-        let mut offset = 0;
+impl<K: HashKey, V: FixedWidth> Iterator for Entries<'_, K, V> {
+    type Item = Result<Entry<K, V>>;
 
-        loop {
-            match file.seek(SeekFrom::Start(offset)) {
-                Ok(_) => {
-                    match file.read_exact(&mut buffer) {
-                        Ok(_) => {
-                            // Read the whole page and find an empty slot
-                            // In the first empty slot, we insert the key + value pair
-                            let mut i = 0;
-                            for chunk in buffer.chunks_exact(100) {
-                                let (key_buf, value_buf) =
-                                    chunk.split_at(std::mem::size_of::<u32>());
-                                let registro_key = u32::from_be_bytes(key_buf.try_into().unwrap());
-                                let registro_value = value_buf
-                                    .iter()
-                                    .map(|&c| c as char)
-                                    .collect::<String>()
-                                    .into_bytes();
-                                new_table_item[i] = Item {
-                                    key: registro_key,
-                                    value: registro_value
-                                        .iter()
-                                        .map(|&c| c as char)
-                                        .collect::<String>()
-                                        .chars()
-                                        .take(96)
-                                        .collect::<Vec<char>>()
-                                        .try_into()
-                                        .unwrap(),
-                                };
-                                i += 1;
-                            }
-                        }
-                        Err(_) => return new_table,
-                    }
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((page, bucket)) = &self.page {
+                if let Some(slot) =
+                    (self.slot..bucket.items.len()).find(|&slot| bucket.items[slot].is_some())
+                {
+                    self.slot = slot + 1;
+                    return Some(Ok(Entry {
+                        bucket: bucket.bucket,
+                        page: *page,
+                        slot,
+                        item: bucket.items[slot].unwrap(),
+                    }));
+                }
+            }
+            match self.next_page() {
+                Ok(Some(page)) => {
+                    self.page = Some(page);
+                    self.slot = 0;
+                }
+                Ok(None) => self.done = true,
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
                 }
-                Err(_) => return new_table,
             }
-            new_table.push(new_table_item);
-            offset += 100 * ITEMS_PER_PAGE as u64;
         }
+        None
     }
+}
 
-    pub fn get_size(&self) -> usize {
-        self.size
+//Only the items of Entries
+pub struct Iter<'a, K, V> {
+    entries: Entries<'a, K, V>,
+}
+
+impl<K: HashKey, V: FixedWidth> Iterator for Iter<'_, K, V> {
+    type Item = Result<Item<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries
+            .next()
+            .map(|entry| entry.map(|entry| entry.item))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn hash(&self, key: u32) -> u64 {
-        (key % self.capacity as u32) as u64
+    fn temporary_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("t02_{}_{}", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned()
     }
-}
\ No newline at end of file
+
+    //Keys whose hashes share their low bits still split on the higher bits that tell them apart
+    #[test]
+    fn keys_sharing_low_bits_still_split() {
+        let file_name = temporary_file("even_keys");
+        let mut table: DynamicHashTable = DynamicHashTable::new(1, file_name.clone()).unwrap();
+        for key in (0..4000).step_by(2) {
+            table.insert(key, ByteString::default()).unwrap();
+        }
+        let stats = table.stats().unwrap();
+        assert!(
+            table.get_global_depth() >= 6,
+            "{}",
+            table.get_global_depth()
+        );
+        assert!(stats.get_buckets() >= 32, "{}", stats.get_buckets());
+        assert_eq!(stats.get_longest_chain(), 1);
+        for key in (0..4000).step_by(2) {
+            assert!(table.read_key_value(key).is_ok());
+        }
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    //Keys that only differ above the deepest directory allowed go to overflow pages instead of
+    //doubling it for nothing
+    #[test]
+    fn clustered_keys_do_not_grow_the_directory() {
+        let file_name = temporary_file("clustered_keys");
+        let mut table: DynamicHashTable = DynamicHashTable::new(1, file_name.clone()).unwrap();
+        table.set_max_directory_depth(8).unwrap();
+        for key in 0..70 {
+            table.insert(key << 22, ByteString::default()).unwrap();
+        }
+        assert_eq!(table.get_global_depth(), 0);
+        let stats = table.stats().unwrap();
+        let slots = Bucket::<u32, ByteString<96>>::SLOTS;
+        assert_eq!(stats.get_longest_chain(), 70usize.div_ceil(slots));
+        assert_eq!(stats.get_buckets(), 1);
+
+        //With the default depth the same keys are spread over their own buckets
+        let reopened_name = temporary_file("clustered_keys_default");
+        let mut spread: DynamicHashTable = DynamicHashTable::new(1, reopened_name.clone()).unwrap();
+        for key in 0..70 {
+            spread.insert(key << 10, ByteString::default()).unwrap();
+        }
+        assert!(spread.get_global_depth() <= DEFAULT_MAX_DIRECTORY_DEPTH);
+        assert_eq!(spread.stats().unwrap().get_longest_chain(), 1);
+
+        let reopened: DynamicHashTable = DynamicHashTable::open(file_name.clone()).unwrap();
+        assert_eq!(reopened.get_max_directory_depth(), 8);
+        for key in 0..70 {
+            assert!(reopened.read_key_value(key << 22).is_ok());
+        }
+        std::fs::remove_file(&file_name).unwrap();
+        std::fs::remove_file(&reopened_name).unwrap();
+    }
+}
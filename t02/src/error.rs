@@ -0,0 +1,53 @@
+use std::fmt;
+use std::io;
+
+//Errors of the storage (Arquivo) and index (DynamicHashTable) layers
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error {
+    //The operating system failed to open, read, write or seek the file
+    Io(io::Error),
+    //No register or item has the requested key
+    NotFound,
+    //The index already has an item with this key (shown with FixedWidth::to_display)
+    DuplicateKey(String),
+    //A page (or register slot in the heap file) has contents that cannot be decoded
+    CorruptPage { page: u64, reason: &'static str },
+    //There is no room left, e.g. every bit of the key is already used by the directory
+    Full(&'static str),
+    //The file was not written by this program or by a compatible version of it
+    FormatMismatch(&'static str),
+    //A row, a schema or a text field breaks its rules (which column or value and why)
+    InvalidRow(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::NotFound => write!(f, "Key not found"),
+            Error::DuplicateKey(key) => write!(f, "Key {} is already stored", key),
+            Error::CorruptPage { page, reason } => write!(f, "Corrupt page {}: {}", page, reason),
+            Error::Full(reason) => write!(f, "No space left: {}", reason),
+            Error::FormatMismatch(reason) => write!(f, "Unexpected file format: {}", reason),
+            Error::InvalidRow(reason) => write!(f, "Invalid row: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
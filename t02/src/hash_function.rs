@@ -0,0 +1,111 @@
+use crate::codec::HashKey;
+use crate::error::{Error, Result};
+
+const KNUTH_MULTIPLIER: u64 = 0x9e3779b97f4a7c15; // 2^64 / golden ratio
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+//Turns a key into the number the bucket is chosen from (by its low bits in extendible mode
+//or by modulo in linear mode), saved in the header so a reopened table hashes the same way
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashFunction {
+    //The hash code of the key as it is, so the table ends up doing key % buckets
+    Modulo,
+    //Knuth's multiplicative hashing, the high half of code * 2^64/phi
+    Multiplicative,
+    //FNV-1a over the bytes of the key as written on disk
+    Fnv1a,
+    //SipHash-2-4 over the bytes of the key, both halves of its 128 bit key come from the seed
+    SipHash { seed: u64 },
+}
+
+impl HashFunction {
+    pub fn hash<K: HashKey>(&self, key: &K) -> u64 {
+        match self {
+            HashFunction::Modulo => key.hash_code(),
+            HashFunction::Multiplicative => key.hash_code().wrapping_mul(KNUTH_MULTIPLIER) >> 32,
+            HashFunction::Fnv1a => fnv1a(&key_bytes(key)),
+            HashFunction::SipHash { seed } => {
+                siphash(*seed, seed.wrapping_mul(KNUTH_MULTIPLIER), &key_bytes(key))
+            }
+        }
+    }
+
+    //Stored in the header as a tag and the seed
+    pub fn to_header(self) -> (u8, u64) {
+        match self {
+            HashFunction::Modulo => (0, 0),
+            HashFunction::Multiplicative => (1, 0),
+            HashFunction::Fnv1a => (2, 0),
+            HashFunction::SipHash { seed } => (3, seed),
+        }
+    }
+
+    pub fn from_header(tag: u8, seed: u64) -> Result<HashFunction> {
+        match tag {
+            0 => Ok(HashFunction::Modulo),
+            1 => Ok(HashFunction::Multiplicative),
+            2 => Ok(HashFunction::Fnv1a),
+            3 => Ok(HashFunction::SipHash { seed }),
+            _ => Err(Error::FormatMismatch(
+                "unknown hash function in hash table header",
+            )),
+        }
+    }
+}
+
+fn key_bytes<K: HashKey>(key: &K) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(K::WIDTH);
+    key.encode(&mut buffer);
+    buffer
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+fn siphash(k0: u64, k1: u64, bytes: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+    let compress = |v: &mut [u64; 4], word: u64| {
+        v[3] ^= word;
+        round(v);
+        round(v);
+        v[0] ^= word;
+    };
+
+    let chunks = bytes.chunks_exact(8);
+    //The last word has the remaining bytes and the length in its top byte
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = bytes.len() as u8;
+    for chunk in chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    compress(&mut v, u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
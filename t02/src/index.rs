@@ -0,0 +1,450 @@
+use crate::codec::FixedWidth;
+use crate::dynamic_hash::{DynamicHashTable, HashMode};
+use crate::error::{Error, Result};
+use crate::hash_function::HashFunction;
+use crate::register::{Arquivo, Nome, Registro, Rid};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+//Alternativa 1: each item of the index keeps the whole nome of the register
+#[allow(dead_code)]
+pub type Alternativa1 = DynamicHashTable<u32, Nome>;
+
+#[allow(dead_code)]
+impl Alternativa1 {
+    //Indexes every register by nseq with a single scan of the file
+    pub fn build(
+        arquivo: &Arquivo,
+        file_name: String,
+        mode: HashMode,
+        hash_function: HashFunction,
+    ) -> Result<Alternativa1> {
+        build_by_nseq(arquivo, file_name, mode, hash_function, |_, registro| {
+            registro.get_nome()
+        })
+    }
+}
+
+//Alternativa 2: each item keeps only the rid of the register in the Arquivo file,
+//so a page holds many more items and a lookup costs one extra access to the heap file
+pub type Alternativa2 = DynamicHashTable<u32, Rid>;
+
+#[allow(dead_code)]
+impl Alternativa2 {
+    //Indexes every register by nseq with a single scan of the file
+    pub fn build(
+        arquivo: &Arquivo,
+        file_name: String,
+        mode: HashMode,
+        hash_function: HashFunction,
+    ) -> Result<Alternativa2> {
+        build_by_nseq(arquivo, file_name, mode, hash_function, |rid, _| rid)
+    }
+
+    //Indexes the register with this nseq, its rid comes from a sequential read of the file
+    pub fn insert_register(&mut self, arquivo: &Arquivo, nseq: u32) -> Result<()> {
+        let rid = arquivo.locate(nseq)?;
+        self.insert(nseq, rid)
+    }
+
+    pub fn read_register(&self, arquivo: &Arquivo, nseq: u32) -> Result<Registro> {
+        let item = self.read_key_value(nseq)?;
+        arquivo.get_by_rid(item.get_value())
+    }
+}
+
+//The table is sized for every register of the file up front, so loading it does not split
+fn build_by_nseq<V: FixedWidth>(
+    arquivo: &Arquivo,
+    file_name: String,
+    mode: HashMode,
+    hash_function: HashFunction,
+    value: impl Fn(Rid, &Registro) -> V,
+) -> Result<DynamicHashTable<u32, V>> {
+    let capacity =
+        DynamicHashTable::<u32, V>::capacity_for(arquivo.get_number_of_registers() as usize);
+    let mut table =
+        DynamicHashTable::new_with_hash_function(capacity, file_name, mode, hash_function)?;
+    let items = arquivo
+        .scan()?
+        .map(|scanned| scanned.map(|(rid, registro)| (registro.get_nseq(), value(rid, &registro))));
+    table.bulk_insert(items)?;
+    Ok(table)
+}
+
+const INLINE_RIDS: usize = 8; // Rids kept in the item itself before spilling
+const RIDS_PER_SPILL_PAGE: usize = 100;
+const SPILL_PAGE_SIZE: usize = 8 + 4 + 8 * RIDS_PER_SPILL_PAGE; // next page + count + rids
+const SPILL_MAGIC: &[u8; 8] = b"T02-RIDS";
+const NO_SPILL_PAGE: u64 = u64::MAX;
+
+//Value of an alternative 3 item: every rid with this key, the first INLINE_RIDS inside the
+//item and the rest in a chain of pages of the spill file
+#[derive(Clone, Copy, Debug)]
+pub struct RidList {
+    count: u32,
+    inline: [Rid; INLINE_RIDS],
+    spill: u64, // First spill page, NO_SPILL_PAGE when every rid is inline
+}
+
+#[allow(dead_code)]
+impl RidList {
+    pub fn get_count(&self) -> u32 {
+        self.count
+    }
+}
+
+impl FixedWidth for RidList {
+    const WIDTH: usize = 4 + 8 * INLINE_RIDS + 8;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.count.to_be_bytes());
+        for rid in &self.inline {
+            rid.encode(buffer);
+        }
+        buffer.extend_from_slice(&self.spill.to_be_bytes());
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        let mut inline = [Rid::default(); INLINE_RIDS];
+        for (i, rid) in inline.iter_mut().enumerate() {
+            *rid = Rid::decode(&buffer[4 + 8 * i..12 + 8 * i]);
+        }
+        RidList {
+            count: u32::decode(&buffer[0..4]),
+            inline,
+            spill: u64::decode(&buffer[Self::WIDTH - 8..]),
+        }
+    }
+
+    fn to_display(&self) -> String {
+        let shown = (self.count as usize).min(INLINE_RIDS);
+        let rids: Vec<String> = self.inline[..shown].iter().map(Rid::to_display).collect();
+        match self.spill {
+            NO_SPILL_PAGE => format!("{} rids [{}]", self.count, rids.join(", ")),
+            page => format!(
+                "{} rids [{}] + spill page {}",
+                self.count,
+                rids.join(", "),
+                page
+            ),
+        }
+    }
+}
+
+//A page of the spill file: the next page of the chain and up to RIDS_PER_SPILL_PAGE rids
+struct SpillPage {
+    next: u64,
+    rids: Vec<Rid>,
+}
+
+//Pages with the rids that do not fit inline, page 0 is the header with the page count and
+//the head of the list of free pages (linked through their next field)
+#[derive(Debug)]
+struct SpillFile {
+    file_name: String,
+    page_count: u64,
+    free_head: u64,
+}
+
+impl SpillFile {
+    fn new(file_name: String) -> Result<SpillFile> {
+        let spill = SpillFile {
+            file_name,
+            page_count: 1,
+            free_head: NO_SPILL_PAGE,
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&spill.file_name)?;
+        spill.write_header(&mut file)?;
+        Ok(spill)
+    }
+
+    fn open(file_name: String) -> Result<SpillFile> {
+        let mut file = OpenOptions::new().read(true).open(&file_name)?;
+        let mut buffer = [0u8; SPILL_PAGE_SIZE];
+        if file.read_exact(&mut buffer).is_err() || &buffer[0..8] != SPILL_MAGIC {
+            return Err(Error::FormatMismatch("file is not a rid spill file"));
+        }
+        let spill = SpillFile {
+            file_name,
+            page_count: u64::decode(&buffer[8..16]),
+            free_head: u64::decode(&buffer[16..24]),
+        };
+        if spill.page_count == 0
+            || file.metadata()?.len() < spill.page_count * SPILL_PAGE_SIZE as u64
+        {
+            return Err(Error::FormatMismatch(
+                "rid spill header does not match the file",
+            ));
+        }
+        Ok(spill)
+    }
+
+    fn open_file(&self) -> Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.file_name)?)
+    }
+
+    fn read_page(&self, file: &mut File, page: u64) -> Result<SpillPage> {
+        if page == 0 || page >= self.page_count {
+            return Err(Error::CorruptPage {
+                page,
+                reason: "spill page outside of the file",
+            });
+        }
+        let mut buffer = [0u8; SPILL_PAGE_SIZE];
+        file.seek(SeekFrom::Start(page * SPILL_PAGE_SIZE as u64))?;
+        file.read_exact(&mut buffer)?;
+        let count = u32::decode(&buffer[8..12]) as usize;
+        if count > RIDS_PER_SPILL_PAGE {
+            return Err(Error::CorruptPage {
+                page,
+                reason: "spill page has more rids than fit in it",
+            });
+        }
+        Ok(SpillPage {
+            next: u64::decode(&buffer[0..8]),
+            rids: buffer[12..12 + 8 * count]
+                .chunks_exact(8)
+                .map(Rid::decode)
+                .collect(),
+        })
+    }
+
+    fn write_page(&self, file: &mut File, page: u64, spill_page: &SpillPage) -> Result<()> {
+        let mut buffer = Vec::with_capacity(SPILL_PAGE_SIZE);
+        spill_page.next.encode(&mut buffer);
+        (spill_page.rids.len() as u32).encode(&mut buffer);
+        for rid in &spill_page.rids {
+            rid.encode(&mut buffer);
+        }
+        buffer.resize(SPILL_PAGE_SIZE, 0);
+        file.seek(SeekFrom::Start(page * SPILL_PAGE_SIZE as u64))?;
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+
+    fn allocate(&mut self, file: &mut File) -> Result<u64> {
+        let page = match self.free_head {
+            NO_SPILL_PAGE => {
+                self.page_count += 1;
+                self.page_count - 1
+            }
+            page => {
+                self.free_head = self.read_page(file, page)?.next;
+                page
+            }
+        };
+        self.write_header(file)?;
+        Ok(page)
+    }
+
+    //Puts the whole chain starting at this page in the free list
+    fn release_chain(&mut self, file: &mut File, mut page: u64) -> Result<()> {
+        let mut released = 0;
+        while page != NO_SPILL_PAGE {
+            released += 1;
+            if released > self.page_count {
+                return Err(Error::CorruptPage {
+                    page,
+                    reason: "spill chain has a cycle",
+                });
+            }
+            let next = self.read_page(file, page)?.next;
+            let free = SpillPage {
+                next: self.free_head,
+                rids: Vec::new(),
+            };
+            self.write_page(file, page, &free)?;
+            self.free_head = page;
+            page = next;
+        }
+        self.write_header(file)
+    }
+
+    fn write_header(&self, file: &mut File) -> Result<()> {
+        let mut buffer = Vec::with_capacity(SPILL_PAGE_SIZE);
+        buffer.extend_from_slice(SPILL_MAGIC);
+        self.page_count.encode(&mut buffer);
+        self.free_head.encode(&mut buffer);
+        buffer.resize(SPILL_PAGE_SIZE, 0);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+//Alternativa 3: each item keeps a key and the list of rids of every register with that key,
+//so attributes that repeat (e.g. a prefix of nome) can be indexed. The spill pages live in a
+//second file named after the index with ".rids" at the end
+#[derive(Debug)]
+pub struct Alternativa3 {
+    table: DynamicHashTable<u32, RidList>,
+    spill: SpillFile,
+}
+
+#[allow(dead_code)]
+impl Alternativa3 {
+    pub fn new_with_mode(
+        initial_capacity: usize,
+        file_name: String,
+        mode: HashMode,
+    ) -> Result<Alternativa3> {
+        let spill = SpillFile::new(format!("{}.rids", file_name))?;
+        let table = DynamicHashTable::new_with_mode(initial_capacity, file_name, mode)?;
+        Ok(Alternativa3 { table, spill })
+    }
+
+    pub fn open(file_name: String) -> Result<Alternativa3> {
+        let spill = SpillFile::open(format!("{}.rids", file_name))?;
+        let table = DynamicHashTable::open(file_name)?;
+        Ok(Alternativa3 { table, spill })
+    }
+
+    pub fn insert(&mut self, key: u32, rid: Rid) -> Result<()> {
+        let mut list = match self.table.read_key_value(key) {
+            Ok(item) => item.get_value(),
+            Err(Error::NotFound) => {
+                let mut inline = [Rid::default(); INLINE_RIDS];
+                inline[0] = rid;
+                let list = RidList {
+                    count: 1,
+                    inline,
+                    spill: NO_SPILL_PAGE,
+                };
+                return self.table.insert(key, list);
+            }
+            Err(error) => return Err(error),
+        };
+
+        if (list.count as usize) < INLINE_RIDS {
+            list.inline[list.count as usize] = rid;
+        } else {
+            //Appends to the last spill page, or chains a new one when it is full
+            let mut file = self.spill.open_file()?;
+            let new_page = SpillPage {
+                next: NO_SPILL_PAGE,
+                rids: vec![rid],
+            };
+            if list.spill == NO_SPILL_PAGE {
+                list.spill = self.spill.allocate(&mut file)?;
+                self.spill.write_page(&mut file, list.spill, &new_page)?;
+            } else {
+                let mut page = list.spill;
+                let mut spill_page = self.spill.read_page(&mut file, page)?;
+                while spill_page.next != NO_SPILL_PAGE {
+                    page = spill_page.next;
+                    spill_page = self.spill.read_page(&mut file, page)?;
+                }
+                if spill_page.rids.len() < RIDS_PER_SPILL_PAGE {
+                    spill_page.rids.push(rid);
+                } else {
+                    spill_page.next = self.spill.allocate(&mut file)?;
+                    self.spill
+                        .write_page(&mut file, spill_page.next, &new_page)?;
+                }
+                self.spill.write_page(&mut file, page, &spill_page)?;
+            }
+        }
+        list.count += 1;
+        self.table.update_value(key, list)
+    }
+
+    pub fn read_rids(&self, key: u32) -> Result<Vec<Rid>> {
+        let list = self.table.read_key_value(key)?.get_value();
+        let mut rids = list.inline[..(list.count as usize).min(INLINE_RIDS)].to_vec();
+        if list.spill != NO_SPILL_PAGE {
+            let mut file = self.spill.open_file()?;
+            let mut page = list.spill;
+            while page != NO_SPILL_PAGE && rids.len() < list.count as usize {
+                let spill_page = self.spill.read_page(&mut file, page)?;
+                rids.extend(spill_page.rids);
+                page = spill_page.next;
+            }
+        }
+        if rids.len() != list.count as usize {
+            return Err(Error::CorruptPage {
+                page: list.spill,
+                reason: "rid list is shorter than its count",
+            });
+        }
+        Ok(rids)
+    }
+
+    //Every register with this key, one access to the heap file for each
+    pub fn read_registers(&self, arquivo: &Arquivo, key: u32) -> Result<Vec<Registro>> {
+        self.read_rids(key)?
+            .into_iter()
+            .map(|rid| arquivo.get_by_rid(rid))
+            .collect()
+    }
+
+    //Removes a single rid of the key, the key leaves the index with its last rid
+    pub fn remove(&mut self, key: u32, rid: Rid) -> Result<()> {
+        let mut rids = self.read_rids(key)?;
+        let position = rids.iter().position(|&r| r == rid).ok_or(Error::NotFound)?;
+        rids.remove(position);
+        if rids.is_empty() {
+            return self.remove_key(key);
+        }
+
+        //The list is rebuilt, the old spill pages go back to the free list and get reused
+        let mut list = self.table.read_key_value(key)?.get_value();
+        let mut file = self.spill.open_file()?;
+        self.spill.release_chain(&mut file, list.spill)?;
+        list.count = rids.len() as u32;
+        list.spill = NO_SPILL_PAGE;
+        for (slot, rid) in list.inline.iter_mut().zip(rids.iter()) {
+            *slot = *rid;
+        }
+        let mut pages: Vec<SpillPage> = rids
+            .get(INLINE_RIDS..)
+            .unwrap_or(&[])
+            .chunks(RIDS_PER_SPILL_PAGE)
+            .map(|chunk| SpillPage {
+                next: NO_SPILL_PAGE,
+                rids: chunk.to_vec(),
+            })
+            .collect();
+        //Written from the end so each page already knows the next one
+        while let Some(mut spill_page) = pages.pop() {
+            spill_page.next = list.spill;
+            list.spill = self.spill.allocate(&mut file)?;
+            self.spill.write_page(&mut file, list.spill, &spill_page)?;
+        }
+        self.table.update_value(key, list)
+    }
+
+    pub fn remove_key(&mut self, key: u32) -> Result<()> {
+        let list = self.table.read_key_value(key)?.get_value();
+        let mut file = self.spill.open_file()?;
+        self.spill.release_chain(&mut file, list.spill)?;
+        self.table.remove_key_value(key)
+    }
+
+    pub fn get_table(&self) -> &DynamicHashTable<u32, RidList> {
+        &self.table
+    }
+
+    pub fn print_all_table(&self) {
+        self.table.print_all_table();
+    }
+}
+
+//Key for indexing registers by the first bytes of nome (up to 4)
+#[allow(dead_code)]
+pub fn prefixo_nome(registro: &Registro, bytes: usize) -> u32 {
+    registro
+        .get_nome()
+        .as_bytes()
+        .iter()
+        .take(bytes.min(4))
+        .fold(0, |key, &byte| (key << 8) | byte as u32)
+}
@@ -1,22 +1,221 @@
+mod codec;
 mod dynamic_hash;
+mod error;
+mod hash_function;
+mod index;
+mod page;
 mod register;
+mod schema;
 //Altere estas constantes se quiser rodar com outros valores
 const BUFFER_SIZE: u32 = 10; // Contado em registros ( cada um de 100 bytes )
 const NUMBER_OF_REGISTERS: u32 = 100;
 const INITIAL_CAPACITY: usize = 3;
 const FILE_NAME: &str = "arquivo_sem_index_por_hash_dinamico";
 const HASH_TABLE_FILE_NAME: &str = "hash_dinamico_alternativa_1";
+const RID_HASH_TABLE_FILE_NAME: &str = "hash_dinamico_alternativa_2";
+const RID_LIST_HASH_TABLE_FILE_NAME: &str = "hash_dinamico_alternativa_3";
+const SCHEMA_FILE_NAME: &str = "arquivo_com_schema";
+const SORTED_FILE_NAME: &str = "arquivo_ordenado_por_nome";
+const HASH_MODE: dynamic_hash::HashMode = dynamic_hash::HashMode::Extendible; // Ou HashMode::Linear
+const HASH_FUNCTION: hash_function::HashFunction = hash_function::HashFunction::Multiplicative; // Ou Modulo, Fnv1a, SipHash { seed }
+const SPLIT_POLICY: dynamic_hash::SplitPolicy = dynamic_hash::SplitPolicy::MaxChainLength(1);
+const MERGE_THRESHOLD: u32 = 50; // Porcentagem de uma página, 0 desliga a junção de buckets
 
 fn main() {
     let arquivo = register::Arquivo::new(NUMBER_OF_REGISTERS, BUFFER_SIZE, FILE_NAME.to_owned());
-    arquivo.write_in_file();
+    if let Err(error) = arquivo.write_in_file() {
+        panic!("Erro ao escrever arquivo: {}", error);
+    }
     println!("Register number 50: {:?}", arquivo.sequential_read(10));
     println!("Register number 40: {:?}", arquivo.sequential_read(11));
+    println!("Block reads so far: {}", arquivo.get_block_reads());
+    let mut arquivo = match register::Arquivo::open(FILE_NAME.to_owned()) {
+        Ok(reopened) => reopened,
+        Err(error) => panic!("Erro ao reabrir arquivo: {}", error),
+    };
+    println!("Arquivo reopened: {:?}", arquivo);
+
+    //Os registros não mudam de lugar, então o rid continua valendo depois de uma remoção
+    let rid = arquivo.insert_at_end();
+    println!("Inserted at rid {:?}", rid);
+    println!("Delete nseq 5: {:?}", arquivo.delete_register(5));
+    println!("Update nseq 20: {:?}", arquivo.update_random(20));
+    println!(
+        "Register at rid of nseq 20: {:?}",
+        arquivo
+            .locate(20)
+            .and_then(|rid| arquivo.get_by_rid(rid))
+            .map(|registro| registro.get_nseq())
+    );
+
+    //Remoção lógica: o registro só é marcado, o espaço volta com o vacuum
+    println!(
+        "Tombstone mode: {:?}",
+        arquivo.set_delete_mode(register::DeleteMode::Tombstone)
+    );
+    println!("Delete nseq 6: {:?}", arquivo.delete_register(6));
+    println!("Delete nseq 100: {:?}", arquivo.delete_register(100));
+    println!("Tombstones: {}", arquivo.get_tombstones());
+    match arquivo.vacuum() {
+        Ok(report) => println!("Vacuum: {}", report),
+        Err(error) => println!("Vacuum failed: {}", error),
+    }
+
+    //Os buracos deixados pelas remoções são preenchidos antes de crescer o arquivo
+    println!(
+        "Set delete mode: {:?}",
+        arquivo.set_delete_mode(register::DeleteMode::Immediate)
+    );
+    println!("Delete nseq 7: {:?}", arquivo.delete_register(7));
+    println!("Free records: {}", arquivo.get_free_records());
+    println!("Inserted in the hole at rid {:?}", arquivo.insert_at_end());
+    println!("Append only: {:?}", arquivo.set_append_only(true));
+    println!("Delete nseq 8: {:?}", arquivo.delete_register(8));
+    println!("Appended at rid {:?}", arquivo.insert_at_end());
+
+    //O mesmo arquivo guarda qualquer tabela, as colunas ficam no cabeçalho
+    let clientes = schema::Schema::new(vec![
+        schema::Column::new("nseq", schema::ColumnType::UInt32),
+        schema::Column::new("nome", schema::ColumnType::VarString(40)),
+        schema::Column::new("saldo", schema::ColumnType::Float64),
+        schema::Column::new("ativo", schema::ColumnType::Bool),
+        schema::Column::new("nascimento", schema::ColumnType::Date),
+    ])
+    .unwrap();
+    let tabela = register::Arquivo::new_with_schema(
+        NUMBER_OF_REGISTERS,
+        BUFFER_SIZE,
+        SCHEMA_FILE_NAME.to_owned(),
+        clientes.clone(),
+    );
+    if let Err(error) = tabela.write_in_file() {
+        panic!("Erro ao escrever arquivo: {}", error);
+    }
+    let mut tabela = match register::Arquivo::open(SCHEMA_FILE_NAME.to_owned()) {
+        Ok(reopened) => reopened,
+        Err(error) => panic!("Erro ao reabrir arquivo: {}", error),
+    };
+    let cliente = register::Registro::from_values(
+        &clientes,
+        vec![
+            schema::Value::UInt32(1000),
+            schema::Value::Text("Maria".to_owned()),
+            schema::Value::Float64(150.25),
+            schema::Value::Bool(true),
+            schema::Value::Date(schema::Date::from_ymd(1990, 4, 21)),
+        ],
+    );
+    println!(
+        "Inserted cliente at rid {:?}",
+        cliente.and_then(|cliente| tabela.insert(&cliente))
+    );
+    match tabela.sequential_read(1000) {
+        Ok(cliente) => println!(
+            "Cliente 1000: {}",
+            cliente
+                .get_values()
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Err(error) => println!("Cliente 1000 not found: {}", error),
+    }
+
+    //Registros que crescem além da página vão para outra e deixam um encaminhamento no slot
+    //A última página recebeu o cliente 1000 além dos seus registros, então não cabem todos
+    let mut rids = Vec::new();
+    for nseq in (NUMBER_OF_REGISTERS - BUFFER_SIZE..NUMBER_OF_REGISTERS).chain([1000]) {
+        let rid = tabela.locate(nseq);
+        let longo = rid.and_then(|rid| {
+            let mut values = tabela.get_by_rid(rid)?.get_values().to_vec();
+            values[1] = schema::Value::Text("N".repeat(40));
+            let cliente = register::Registro::from_values(&clientes, values)?;
+            tabela.update_by_rid(rid, &cliente).map(|_| rid)
+        });
+        match longo {
+            Ok(rid) => rids.push(rid),
+            Err(error) => println!("Update cliente {} failed: {}", nseq, error),
+        }
+    }
+    tabela.reset_block_reads();
+    let lidos = rids
+        .iter()
+        .filter(|&&rid| tabela.get_by_rid(rid).is_ok())
+        .count();
+    println!(
+        "Clientes with long nomes read at their old rids: {} in {} block reads",
+        lidos,
+        tabela.get_block_reads()
+    );
 
-    let mut hash_table = match dynamic_hash::DynamicHashTable::new(INITIAL_CAPACITY, HASH_TABLE_FILE_NAME.to_owned()) {
+    //Arquivo ordenado por nseq: as buscas são binárias sobre as páginas
+    let busca = tabela.search(50);
+    println!(
+        "Heap search nseq 50: {} block reads",
+        busca.map_or(0, |busca| busca.get_block_reads())
+    );
+    println!(
+        "Sorted organization: {:?}",
+        tabela.set_organization(register::Organization::Sorted)
+    );
+    match tabela.search(50) {
+        Ok(busca) => println!(
+            "Sorted search nseq 50: {:?} in {} block reads",
+            busca.get_registro().map(|cliente| cliente.get_nseq()),
+            busca.get_block_reads()
+        ),
+        Err(error) => println!("Sorted search failed: {}", error),
+    }
+    println!("Delete nseq 30: {:?}", tabela.delete_register(30));
+    let cliente = register::Registro::from_values(&clientes, clientes.random_row(30));
+    println!(
+        "Inserted nseq 30 back at rid {:?}",
+        cliente.and_then(|cliente| tabela.insert(&cliente))
+    );
+    match tabela.search_range(25, 35) {
+        Ok(busca) => println!(
+            "Sorted search nseqs 25 to 35: {:?} in {} block reads",
+            busca
+                .get_results()
+                .iter()
+                .map(|(_, cliente)| cliente.get_nseq())
+                .collect::<Vec<_>>(),
+            busca.get_block_reads()
+        ),
+        Err(error) => println!("Sorted range search failed: {}", error),
+    }
+
+    //Ordenação externa: só buffer_size páginas ficam na memória, o resto vai para runs
+    match tabela.sort_by("nome", SORTED_FILE_NAME.to_owned()) {
+        Ok((ordenado, report)) => {
+            println!("Sort by nome: {}", report);
+            let nomes = ordenado.scan().map(|scan| {
+                scan.take(3)
+                    .filter_map(|scanned| scanned.ok())
+                    .map(|(_, cliente)| cliente.get_values()[1].to_string())
+                    .collect::<Vec<_>>()
+            });
+            println!("First nomes: {:?}", nomes);
+        }
+        Err(error) => println!("Sort by nome failed: {}", error),
+    }
+
+    let mut hash_table = match index::Alternativa1::new_with_hash_function(
+        INITIAL_CAPACITY,
+        HASH_TABLE_FILE_NAME.to_owned(),
+        HASH_MODE,
+        HASH_FUNCTION,
+    ) {
         Ok(hash_table) => hash_table,
-        Err(_) => panic!("Erro ao criar hash"),
+        Err(error) => panic!("Erro ao criar hash: {}", error),
     };
+    if let Err(error) = hash_table
+        .set_split_policy(SPLIT_POLICY)
+        .and_then(|_| hash_table.set_merge_threshold(MERGE_THRESHOLD))
+    {
+        panic!("Erro ao configurar hash: {}", error);
+    }
     println!(
         "{:?}",
         hash_table.insert(10, arquivo.sequential_read(10).unwrap().get_nome())
@@ -37,10 +236,93 @@ fn main() {
     println!("-------------------------");
     println!("Hash table nseq 10: {:?}", hash_table.read_key_value(10));
     println!("Hash table nseq 11: {:?}", hash_table.read_key_value(11));
-    println!("Hash table remove 10: {:?}", hash_table.remove_key_value(10));
+    println!(
+        "Hash table remove 10: {:?}",
+        hash_table.remove_key_value(10)
+    );
     println!("Hash table nseq 10: {:?}", hash_table.read_key_value(10));
     println!("-------------------------");
 
     hash_table.print_all_table();
+    match hash_table.entries() {
+        Ok(entries) => {
+            for entry in entries {
+                match entry {
+                    Ok(entry) => println!(
+                        "Bucket {} page {} slot {}: key {}",
+                        entry.get_bucket(),
+                        entry.get_page(),
+                        entry.get_slot(),
+                        entry.get_item().get_key()
+                    ),
+                    Err(error) => println!("Erro ao percorrer hash: {}", error),
+                }
+            }
+        }
+        Err(error) => println!("Erro ao percorrer hash: {}", error),
+    }
 
+    match hash_table.stats() {
+        Ok(stats) => print!("{}", stats),
+        Err(error) => println!("Erro ao ler estatísticas: {}", error),
+    }
+
+    //O índice fica salvo no arquivo e pode ser reaberto em outra execução
+    match index::Alternativa1::open(HASH_TABLE_FILE_NAME.to_owned()) {
+        Ok(reopened) => println!(
+            "Hash table reopened with {} items, nseq 11: {:?}",
+            reopened.get_size(),
+            reopened.read_key_value(11).map(|item| item.get_key())
+        ),
+        Err(error) => println!("Erro ao reabrir hash: {}", error),
+    }
+
+    //Alternativa 2: o índice guarda apenas o rid do registro no arquivo, todos os registros
+    //entram com uma única leitura do arquivo
+    let rid_table = match index::Alternativa2::build(
+        &arquivo,
+        RID_HASH_TABLE_FILE_NAME.to_owned(),
+        HASH_MODE,
+        HASH_FUNCTION,
+    ) {
+        Ok(rid_table) => rid_table,
+        Err(error) => panic!("Erro ao criar hash: {}", error),
+    };
+    match rid_table.stats() {
+        Ok(stats) => print!("{}", stats),
+        Err(error) => println!("Erro ao ler estatísticas: {}", error),
+    }
+    println!(
+        "Hash table (alternativa 2) nseq 12: {:?}",
+        rid_table
+            .read_register(&arquivo, 12)
+            .map(|registro| registro.get_nseq())
+    );
+
+    //Alternativa 3: chave repetida (primeira letra do nome) com a lista de rids dos registros
+    let mut rid_list_table = match index::Alternativa3::new_with_mode(
+        INITIAL_CAPACITY,
+        RID_LIST_HASH_TABLE_FILE_NAME.to_owned(),
+        HASH_MODE,
+    ) {
+        Ok(rid_list_table) => rid_list_table,
+        Err(error) => panic!("Erro ao criar hash: {}", error),
+    };
+    let indexed = arquivo.scan().and_then(|scan| {
+        for scanned in scan {
+            let (rid, registro) = scanned?;
+            rid_list_table.insert(index::prefixo_nome(&registro, 1), rid)?;
+        }
+        Ok(())
+    });
+    if let Err(error) = indexed {
+        println!("Erro ao indexar arquivo: {}", error);
+    }
+    let prefixo = index::prefixo_nome(&arquivo.sequential_read(12).unwrap(), 1);
+    println!(
+        "Hash table (alternativa 3) nseqs with the first char of nseq 12: {:?}",
+        rid_list_table
+            .read_registers(&arquivo, prefixo)
+            .map(|registros| registros.iter().map(|r| r.get_nseq()).collect::<Vec<_>>())
+    );
 }
@@ -0,0 +1,363 @@
+use crate::error::{Error, Result};
+
+//Slot count (u32) + start of the record area (u32) + next page of the free list (u32)
+pub const PAGE_HEADER_SIZE: usize = 12;
+//End of the free list
+pub const NO_PAGE: u32 = u32::MAX;
+//Offset (u32) + length (u32) of a record, an offset of 0 marks a free slot
+pub const SLOT_ENTRY_SIZE: usize = 8;
+const FREE_SLOT: u32 = 0;
+//High bits of the length say what the slot holds. TOMBSTONE: the record was deleted but still
+//takes its space until a purge. FORWARD: the record grew out of the page, the slot keeps the
+//rid of where it went. MOVED: a record that lives here for the slot that forwards to it
+const TOMBSTONE: u32 = 1 << 31;
+const FORWARD: u32 = 1 << 30;
+const MOVED: u32 = 1 << 29;
+const LENGTH_MASK: u32 = MOVED - 1;
+//Shorter records still take this much room, so a forward can always replace them
+pub const MIN_RECORD_ROOM: usize = 8;
+
+//Slotted page of the heap file: the header, then the slot directory growing forwards and the
+//records growing backwards from the end of the page. The free space is the gap between them.
+//A record keeps its slot number while it lives, only its offset changes when the page is
+//compacted, so (page, slot) works as a stable rid
+#[derive(Clone, Debug)]
+pub struct Page {
+    data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl Page {
+    pub fn new(page_size: usize) -> Page {
+        let mut page = Page {
+            data: vec![0; page_size],
+        };
+        page.set_free_space_end(page_size);
+        page.set_next_free(NO_PAGE);
+        page
+    }
+
+    //number is only used to report a corrupt page
+    pub fn from_bytes(number: u64, data: Vec<u8>) -> Result<Page> {
+        let page = Page { data };
+        let corrupt = |reason| Error::CorruptPage {
+            page: number,
+            reason,
+        };
+        let directory_end = PAGE_HEADER_SIZE + page.slot_count() as usize * SLOT_ENTRY_SIZE;
+        if directory_end > page.free_space_end() || page.free_space_end() > page.data.len() {
+            return Err(corrupt("slot directory overlaps the records"));
+        }
+        for slot in 0..page.slot_count() {
+            if let Some((offset, length, _)) = page.entry(slot) {
+                if offset < page.free_space_end() || offset + length > page.data.len() {
+                    return Err(corrupt("record outside of the record area"));
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn slot_count(&self) -> u32 {
+        self.u32_at(0)
+    }
+
+    //Bytes left for a new record, counting the slot entry it may need
+    pub fn free_space(&self) -> usize {
+        self.free_space_end() - PAGE_HEADER_SIZE - self.slot_count() as usize * SLOT_ENTRY_SIZE
+    }
+
+    //Pages with a free slot are linked from the file header through this field
+    pub fn get_next_free(&self) -> u32 {
+        self.u32_at(8)
+    }
+
+    pub fn set_next_free(&mut self, page: u32) {
+        self.set_u32(8, page);
+    }
+
+    pub fn has_free_slot(&self) -> bool {
+        (0..self.slot_count()).any(|slot| self.slot(slot).0 == FREE_SLOT)
+    }
+
+    //Whether a record of this length fits, reusing a free slot entry if there is one
+    pub fn fits(&self, length: usize) -> bool {
+        let entry = if self.has_free_slot() {
+            0
+        } else {
+            SLOT_ENTRY_SIZE
+        };
+        self.free_space() >= room(length) + entry
+    }
+
+    pub fn record(&self, slot: u32) -> Option<&[u8]> {
+        self.holding(slot, 0)
+    }
+
+    //Rid of where the record of this slot went, as stored by forward_to
+    pub fn forward(&self, slot: u32) -> Option<&[u8]> {
+        self.holding(slot, FORWARD)
+    }
+
+    //Record kept here for a slot of another page
+    pub fn moved(&self, slot: u32) -> Option<&[u8]> {
+        self.holding(slot, MOVED)
+    }
+
+    //Live records with their slots, in slot order
+    pub fn records(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        (0..self.slot_count()).filter_map(|slot| self.record(slot).map(|record| (slot, record)))
+    }
+
+    //Stores the record in the first free slot (or a new one), None if it does not fit
+    pub fn insert(&mut self, record: &[u8]) -> Option<u32> {
+        self.insert_with(record, 0)
+    }
+
+    //Same as insert, for a record that another page forwards to
+    pub fn insert_moved(&mut self, record: &[u8]) -> Option<u32> {
+        self.insert_with(record, MOVED)
+    }
+
+    //Stores the record in a new slot after every other one, even if a slot is free. None if
+    //it does not fit
+    pub fn append(&mut self, record: &[u8]) -> Option<u32> {
+        self.append_with(record, 0)
+    }
+
+    pub fn append_moved(&mut self, record: &[u8]) -> Option<u32> {
+        self.append_with(record, MOVED)
+    }
+
+    //Frees the slot and closes the gap its record (or forward) leaves in the record area. A
+    //moved record belongs to the slot that forwards to it, see release_moved
+    pub fn delete(&mut self, slot: u32) -> bool {
+        match self.entry(slot) {
+            Some((offset, length, flags)) if flags & (TOMBSTONE | MOVED) == 0 => {
+                self.free(slot, offset, length);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    //Frees the slot of a moved record, once the slot forwarding to it is gone
+    pub fn release_moved(&mut self, slot: u32) -> bool {
+        match self.entry(slot) {
+            Some((offset, length, MOVED)) => {
+                self.free(slot, offset, length);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn free(&mut self, slot: u32, offset: usize, length: usize) {
+        self.set_slot(slot, FREE_SLOT, 0);
+        self.close_gap(offset, room(length));
+    }
+
+    //Logical delete: the record (or forward) stays where it is, only its slot is flagged
+    pub fn mark_deleted(&mut self, slot: u32) -> bool {
+        match self.entry(slot) {
+            Some((offset, length, flags)) if flags == 0 || flags == FORWARD => {
+                self.set_slot(slot, offset as u32, length as u32 | flags | TOMBSTONE);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn tombstones(&self) -> u32 {
+        (0..self.slot_count())
+            .filter(|&slot| self.is_tombstone(slot))
+            .count() as u32
+    }
+
+    //Frees the slots of every tombstone, returns how many there were and the bytes reclaimed
+    pub fn purge(&mut self) -> (u32, usize) {
+        let mut purged = (0, 0);
+        for slot in 0..self.slot_count() {
+            if self.is_tombstone(slot) {
+                let (offset, length, _) = self.entry(slot).unwrap();
+                self.set_slot(slot, FREE_SLOT, 0);
+                self.close_gap(offset, room(length));
+                purged = (purged.0 + 1, purged.1 + room(length));
+            }
+        }
+        purged
+    }
+
+    pub fn free_slots(&self) -> u32 {
+        (0..self.slot_count())
+            .filter(|&slot| self.slot(slot).0 == FREE_SLOT)
+            .count() as u32
+    }
+
+    //No slot holds anything, not even a moved record
+    pub fn is_empty(&self) -> bool {
+        self.free_slots() == self.slot_count()
+    }
+
+    //Whether the slot can take contents of this length in place of what it has now
+    pub fn can_hold(&self, slot: u32, length: usize) -> bool {
+        match self.entry(slot) {
+            Some((_, current, _)) => self.free_space() + room(current) >= room(length),
+            None => false,
+        }
+    }
+
+    //Replaces the record (plain or moved) keeping its slot, false if the new one does not fit
+    pub fn update(&mut self, slot: u32, record: &[u8]) -> bool {
+        match self.entry(slot) {
+            Some((_, _, flags)) if flags == 0 || flags == MOVED => self.put(slot, record, flags),
+            _ => false,
+        }
+    }
+
+    //Puts the record back in a slot that may have been forwarded
+    pub fn set_record(&mut self, slot: u32, record: &[u8]) -> bool {
+        match self.entry(slot) {
+            Some((_, _, flags)) if flags == 0 || flags == FORWARD => self.put(slot, record, 0),
+            _ => false,
+        }
+    }
+
+    //The record of the slot now lives somewhere else, the slot keeps only where
+    pub fn forward_to(&mut self, slot: u32, location: &[u8]) -> bool {
+        match self.entry(slot) {
+            Some((_, _, flags)) if flags == 0 || flags == FORWARD => {
+                self.put(slot, location, FORWARD)
+            }
+            _ => false,
+        }
+    }
+
+    fn insert_with(&mut self, record: &[u8], flags: u32) -> Option<u32> {
+        if !self.fits(record.len()) {
+            return None;
+        }
+        let slot = match (0..self.slot_count()).find(|&slot| self.slot(slot).0 == FREE_SLOT) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slot_count();
+                self.set_u32(0, slot + 1);
+                slot
+            }
+        };
+        self.place(slot, record, flags);
+        Some(slot)
+    }
+
+    fn append_with(&mut self, record: &[u8], flags: u32) -> Option<u32> {
+        if self.free_space() < room(record.len()) + SLOT_ENTRY_SIZE {
+            return None;
+        }
+        let slot = self.slot_count();
+        self.set_u32(0, slot + 1);
+        self.place(slot, record, flags);
+        Some(slot)
+    }
+
+    //Replaces the contents of a used slot, moving them to the free space if the length changes
+    fn put(&mut self, slot: u32, contents: &[u8], flags: u32) -> bool {
+        let (offset, length, _) = self.entry(slot).unwrap();
+        if room(contents.len()) == room(length) {
+            self.data[offset..offset + contents.len()].copy_from_slice(contents);
+            self.set_slot(slot, offset as u32, contents.len() as u32 | flags);
+            return true;
+        }
+        if self.free_space() + room(length) < room(contents.len()) {
+            return false;
+        }
+        self.close_gap(offset, room(length));
+        self.place(slot, contents, flags);
+        true
+    }
+
+    //Writes the contents right before the record area, which grows over them
+    fn place(&mut self, slot: u32, contents: &[u8], flags: u32) {
+        let offset = self.free_space_end() - room(contents.len());
+        self.data[offset..offset + contents.len()].copy_from_slice(contents);
+        self.set_free_space_end(offset);
+        self.set_slot(slot, offset as u32, contents.len() as u32 | flags);
+    }
+
+    //Contents of a used slot with exactly these flags
+    fn holding(&self, slot: u32, wanted: u32) -> Option<&[u8]> {
+        match self.entry(slot) {
+            Some((offset, length, flags)) if flags == wanted => {
+                Some(&self.data[offset..offset + length])
+            }
+            _ => None,
+        }
+    }
+
+    //Offset, length and flags of a used slot
+    fn entry(&self, slot: u32) -> Option<(usize, usize, u32)> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        match self.slot(slot) {
+            (FREE_SLOT, _) => None,
+            (offset, length) => Some((
+                offset as usize,
+                (length & LENGTH_MASK) as usize,
+                length & !LENGTH_MASK,
+            )),
+        }
+    }
+
+    //Moves the records below the gap up by its room
+    fn close_gap(&mut self, offset: usize, length: usize) {
+        let start = self.free_space_end();
+        self.data.copy_within(start..offset, start + length);
+        for other in 0..self.slot_count() {
+            let (other_offset, other_length) = self.slot(other);
+            if other_offset != FREE_SLOT && (other_offset as usize) < offset {
+                self.set_slot(other, other_offset + length as u32, other_length);
+            }
+        }
+        self.set_free_space_end(start + length);
+    }
+
+    fn is_tombstone(&self, slot: u32) -> bool {
+        self.entry(slot)
+            .is_some_and(|(_, _, flags)| flags & TOMBSTONE != 0)
+    }
+
+    fn free_space_end(&self) -> usize {
+        self.u32_at(4) as usize
+    }
+
+    fn set_free_space_end(&mut self, end: usize) {
+        self.set_u32(4, end as u32);
+    }
+
+    fn slot(&self, slot: u32) -> (u32, u32) {
+        let position = PAGE_HEADER_SIZE + slot as usize * SLOT_ENTRY_SIZE;
+        (self.u32_at(position), self.u32_at(position + 4))
+    }
+
+    fn set_slot(&mut self, slot: u32, offset: u32, length: u32) {
+        let position = PAGE_HEADER_SIZE + slot as usize * SLOT_ENTRY_SIZE;
+        self.set_u32(position, offset);
+        self.set_u32(position + 4, length);
+    }
+
+    fn u32_at(&self, position: usize) -> u32 {
+        u32::from_be_bytes(self.data[position..position + 4].try_into().unwrap())
+    }
+
+    fn set_u32(&mut self, position: usize, value: u32) {
+        self.data[position..position + 4].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn room(length: usize) -> usize {
+    length.max(MIN_RECORD_ROOM)
+}
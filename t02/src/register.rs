@@ -1,46 +1,165 @@
-use rand::{distributions::Alphanumeric, Rng}; //rand = "0.8.5"
+use crate::codec::{ByteString, FixedWidth};
+use crate::error::{Error, Result};
+use crate::page::{Page, MIN_RECORD_ROOM, NO_PAGE, PAGE_HEADER_SIZE, SLOT_ENTRY_SIZE};
+use crate::schema::{Schema, Value};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::Path;
 
-#[derive(Debug)]
+const MAGIC: &[u8; 8] = b"T02-HEAP";
+const FORMAT_VERSION: u32 = 8;
+const HEADER_SIZE: u64 = 100; // Fixed fields of the header, the schema comes right after them
+const DEFAULT_MAX_OVERFLOW_PAGES: u32 = 4;
+
+//Register id: the page and the slot of its directory that points to the register. The slot
+//of a register never changes, so a rid stays valid until its register is deleted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rid {
+    page: u32,
+    slot: u32,
+}
+
+#[allow(dead_code)]
+impl Rid {
+    pub fn new(page: u32, slot: u32) -> Rid {
+        Rid { page, slot }
+    }
+
+    pub fn get_page(&self) -> u32 {
+        self.page
+    }
+
+    pub fn get_slot(&self) -> u32 {
+        self.slot
+    }
+}
+
+impl FixedWidth for Rid {
+    const WIDTH: usize = 8;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.page.encode(buffer);
+        self.slot.encode(buffer);
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        Rid {
+            page: u32::decode(&buffer[0..4]),
+            slot: u32::decode(&buffer[4..8]),
+        }
+    }
+
+    fn to_display(&self) -> String {
+        format!("({}, {})", self.page, self.slot)
+    }
+}
+
+//nome of the default schema, as kept by Registro::get_nome and the items of alternative 1
+pub type Nome = ByteString<96>;
+
+//A row of an Arquivo, one value per column of its schema. The first value is the nseq
+#[derive(Clone, Debug, PartialEq)]
 pub struct Registro {
-    nseq: u32,
-    nome: [char; 96],
+    values: Vec<Value>,
 }
 
 #[allow(dead_code)]
 impl Registro {
-    fn new(nseq: u32) -> Registro {
-        let rng = rand::thread_rng();
-        let arr: [char; 96] = rng
-            .sample_iter(Alphanumeric)
-            .take(96)
-            .map(char::from)
-            .collect::<Vec<char>>()
-            .try_into()
-            .unwrap();
-        Registro { nseq, nome: arr }
-    }
-
-    fn update(&mut self, nseq: u32) {
-        self.nseq = nseq;
-        let rng = rand::thread_rng();
-        self.nome = rng
-            .sample_iter(Alphanumeric)
-            .take(96)
-            .map(char::from)
-            .collect::<Vec<char>>()
-            .try_into()
-            .unwrap();
+    //Register of the default schema with a random nome
+    pub fn new(nseq: u32) -> Registro {
+        Registro {
+            values: Schema::default().random_row(nseq),
+        }
+    }
+
+    pub fn from_values(schema: &Schema, values: Vec<Value>) -> Result<Registro> {
+        schema.check(&values)?;
+        Ok(Registro { values })
     }
 
     pub fn get_nseq(&self) -> u32 {
-        self.nseq
+        match self.values.first() {
+            Some(Value::UInt32(nseq)) => *nseq,
+            _ => unreachable!("schemas start with a UInt32 key"),
+        }
     }
 
-    pub fn get_nome(&self) -> [char; 96] {
-        self.nome
+    //Second column as the nome of the default schema, cut to 96 bytes for other schemas
+    pub fn get_nome(&self) -> Nome {
+        match self.values.get(1) {
+            Some(Value::Text(text)) => Nome::truncated(text),
+            _ => Nome::default(),
+        }
+    }
+
+    pub fn get_value(&self, column: usize) -> Option<&Value> {
+        self.values.get(column)
+    }
+
+    pub fn get_values(&self) -> &[Value] {
+        &self.values
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteMode {
+    //The slot is freed and the page compacted right away
+    Immediate,
+    //The register is only marked as deleted, its space comes back with vacuum
+    Tombstone,
+}
+
+impl DeleteMode {
+    fn to_header(self) -> u8 {
+        match self {
+            DeleteMode::Immediate => 0,
+            DeleteMode::Tombstone => 1,
+        }
+    }
+
+    fn from_header(tag: u8) -> Result<DeleteMode> {
+        match tag {
+            0 => Ok(DeleteMode::Immediate),
+            1 => Ok(DeleteMode::Tombstone),
+            _ => Err(Error::FormatMismatch(
+                "unknown delete mode in register file header",
+            )),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Organization {
+    //Registers go wherever there is room and lookups scan the whole file
+    Heap,
+    //The first sorted_pages pages keep the registers in nseq order, so lookups are binary
+    //searches. Inserts that do not fit in their page wait in an overflow area at the end of the
+    //file until it is merged. Inserts and merges move registers, so rids are not stable
+    Sorted,
+}
+
+impl Organization {
+    fn to_header(self) -> u8 {
+        match self {
+            Organization::Heap => 0,
+            Organization::Sorted => 1,
+        }
+    }
+
+    fn from_header(tag: u8) -> Result<Organization> {
+        match tag {
+            0 => Ok(Organization::Heap),
+            1 => Ok(Organization::Sorted),
+            _ => Err(Error::FormatMismatch(
+                "unknown organization in register file header",
+            )),
+        }
     }
 }
 
@@ -48,206 +167,1710 @@ impl Registro {
 #[derive(Debug)]
 pub struct Arquivo {
     file_name: String,
-    file_size: u32,
-    buffer_size: u32,
+    schema: Schema,
+    file_size: u32,    // Live registers
+    buffer_size: u32,  // Registers that fit in a page, so a block read brings one page
+    free_records: u32, // Slots freed by deletes, reused by later inserts
+    free_head: u32,    // First page of the list of pages with a free slot
+    append_only: bool, // Inserts never fill holes, so scans see registers in insertion order
+    page_count: u32,   // Pages after the header
+    next_nseq: u32,    // Inserted registers get increasing nseqs, never repeated
+    delete_mode: DeleteMode,
+    tombstones: u32, // Registers marked as deleted and not vacuumed yet
+    organization: Organization,
+    sorted_pages: u32, // Pages in nseq order, the overflow area comes after them
+    max_overflow_pages: u32, // The overflow area is merged before it grows past this
+    block_reads: Cell<u64>, // Page reads since creation or the last reset
 }
 
 #[allow(dead_code)]
 impl Arquivo {
-    //Buffer size is measured in amount of registers, every register has 100 bytes
+    //Buffer size is measured in amount of registers, every register of the default schema has
+    //100 bytes
     pub fn new(number_of_registers: u32, buffer_size: u32, file_name: String) -> Arquivo {
+        Arquivo::new_with_schema(
+            number_of_registers,
+            buffer_size,
+            file_name,
+            Schema::default(),
+        )
+    }
+
+    //The registers written by write_in_file get nseqs from 0 and random values
+    pub fn new_with_schema(
+        number_of_registers: u32,
+        buffer_size: u32,
+        file_name: String,
+        schema: Schema,
+    ) -> Arquivo {
         Arquivo {
             file_name,
+            schema,
             file_size: number_of_registers,
             buffer_size,
+            free_records: 0,
+            free_head: NO_PAGE,
+            append_only: false,
+            page_count: number_of_registers.div_ceil(buffer_size.max(1)),
+            next_nseq: number_of_registers,
+            delete_mode: DeleteMode::Immediate,
+            tombstones: 0,
+            organization: Organization::Heap,
+            sorted_pages: 0,
+            max_overflow_pages: DEFAULT_MAX_OVERFLOW_PAGES,
+            block_reads: Cell::new(0),
         }
     }
 
-    pub fn write_in_file(&self) {
-        let mut end = self.file_size / self.buffer_size;
-        if self.file_size % self.buffer_size != 0 {
-            end += 1;
+    //Reopens a file written by a previous run, everything comes from its header
+    pub fn open(file_name: String) -> Result<Arquivo> {
+        let mut file = OpenOptions::new().read(true).open(&file_name)?;
+        let mut buffer = [0u8; HEADER_SIZE as usize];
+        if file.read_exact(&mut buffer).is_err() {
+            return Err(Error::FormatMismatch("file is too small to have a header"));
+        }
+        if &buffer[0..8] != MAGIC {
+            return Err(Error::FormatMismatch("file is not a register file"));
+        }
+        let u32_at = |pos: usize| u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap());
+        if u32_at(8) != FORMAT_VERSION {
+            return Err(Error::FormatMismatch(
+                "unsupported register file format version",
+            ));
+        }
+        let mut schema_bytes = vec![0u8; u16::from_be_bytes([buffer[50], buffer[51]]) as usize];
+        if file.read_exact(&mut schema_bytes).is_err() {
+            return Err(Error::FormatMismatch("file is too small to have a schema"));
         }
-        let mut break_argument = self.file_size as i64;
+        let schema = Schema::from_bytes(&schema_bytes)?;
+        if u32_at(12) as usize != schema.max_row_size() {
+            return Err(Error::FormatMismatch("register length does not match"));
+        }
+        let arquivo = Arquivo {
+            file_name,
+            schema,
+            file_size: u32_at(20),
+            buffer_size: u32_at(16),
+            free_records: u32_at(24),
+            free_head: u32_at(45),
+            append_only: buffer[49] != 0,
+            page_count: u32_at(28),
+            next_nseq: u32_at(36),
+            delete_mode: DeleteMode::from_header(buffer[44])?,
+            tombstones: u32_at(40),
+            organization: Organization::from_header(buffer[52])?,
+            sorted_pages: u32_at(53),
+            max_overflow_pages: u32_at(57),
+            block_reads: Cell::new(0),
+        };
+        if arquivo.buffer_size == 0
+            || arquivo.sorted_pages > arquivo.page_count
+            || u32_at(32) as u64 != arquivo.page_size()
+            || arquivo.get_file_size()? < arquivo.page_position(arquivo.page_count)
+        {
+            return Err(Error::FormatMismatch(
+                "register file header does not match the file",
+            ));
+        }
+        Ok(arquivo)
+    }
 
+    pub fn write_in_file(&self) -> Result<()> {
+        if self.buffer_size == 0 {
+            return Err(Error::Full("buffer size must hold at least one register"));
+        }
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&self.file_name)
-            .unwrap();
+            .truncate(true)
+            .open(&self.file_name)?;
+        file.write_all(&self.header_bytes())?;
+
+        //One page of buffer_size registers per write, the last one may have free space
+        let mut nseq = 0;
+        while nseq < self.file_size {
+            let end = nseq.saturating_add(self.buffer_size).min(self.file_size);
+            let mut page = Page::new(self.page_size() as usize);
+            for nseq in nseq..end {
+                page.insert(&self.schema.encode(&self.schema.random_row(nseq))?);
+            }
+            file.write_all(page.as_bytes())?;
+            nseq = end;
+        }
+        Ok(())
+    }
 
-        for i in 0..end {
-            let mut registros: Vec<Registro> = Vec::new();
-            for j in 0..self.buffer_size {
-                let registro = Registro::new(i * self.buffer_size + j);
-                registros.push(registro);
+    //Finds the register with the nseq given: a sequential read of a heap file, a binary search
+    //over the pages of a sorted one
+    pub fn sequential_read(&self, nseq: u32) -> Result<Registro> {
+        self.find(nseq).map(|(_, registro)| registro)
+    }
 
-                if break_argument <= 0 {
-                    //Only used in cases the file size is not a multiple of the buffer size
-                    break;
-                }
+    //Rid of the register with the nseq given, found the same way
+    pub fn locate(&self, nseq: u32) -> Result<Rid> {
+        self.find(nseq).map(|(rid, _)| rid)
+    }
+
+    //Direct access, a single page read, or two when the register was forwarded
+    pub fn get_by_rid(&self, rid: Rid) -> Result<Registro> {
+        let mut file = self.get_file()?;
+        let page = self.read_page(&mut file, rid.page)?;
+        match (page.record(rid.slot), page.forward(rid.slot)) {
+            (Some(record), _) => self.decode(rid.page, record),
+            (None, Some(location)) => self.read_moved(&mut file, location),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    //Overwrites the register, its rid does not change. When it grows out of its page it goes
+    //to another page and its slot keeps a forward to it, and it comes back when it fits again
+    pub fn update_by_rid(&mut self, rid: Rid, registro: &Registro) -> Result<()> {
+        let record = self.schema.encode(&registro.values)?;
+        if self.organization == Organization::Sorted {
+            return self.update_sorted(rid, registro.get_nseq(), &record);
+        }
+        let mut file = self.get_file()?;
+        let mut page = self.read_page(&mut file, rid.page)?;
+        let moved_to = match (page.record(rid.slot), page.forward(rid.slot)) {
+            (Some(_), _) => None,
+            (None, Some(location)) => Some(Rid::decode(location)),
+            _ => return Err(Error::NotFound),
+        };
+        if page.can_hold(rid.slot, record.len()) {
+            page.set_record(rid.slot, &record);
+            self.write_page(&mut file, rid.page, &page)?;
+            if let Some(moved_to) = moved_to {
+                self.release_moved(&mut file, moved_to)?;
+                self.write_header(&mut file)?;
             }
-            break_argument -= self.buffer_size as i64;
-            let mut vec = Vec::new();
-            for registro in &registros {
-                let mut nseq = registro.nseq.to_be_bytes().to_vec();
-                let mut nome = registro.nome.iter().collect::<String>().into_bytes();
-                vec.append(&mut nseq);
-                vec.append(&mut nome);
+            return Ok(());
+        }
+        if let Some(moved_to) = moved_to {
+            let mut moved_page = self.read_page(&mut file, moved_to.page)?;
+            if moved_page.update(moved_to.slot, &record) {
+                return self.write_page(&mut file, moved_to.page, &moved_page);
             }
-            file.write_all(&vec).unwrap();
         }
+
+        if !page.can_hold(rid.slot, Rid::WIDTH) {
+            return Err(Error::Full("no room in the page to forward the register"));
+        }
+        //The home page cannot hold the record, so store never picks it
+        let location = self.store(&mut file, &record, true)?;
+        if let Some(moved_to) = moved_to {
+            self.release_moved(&mut file, moved_to)?;
+        }
+        let mut buffer = Vec::with_capacity(Rid::WIDTH);
+        location.encode(&mut buffer);
+        page.forward_to(rid.slot, &buffer);
+        self.write_page(&mut file, rid.page, &page)?;
+        self.write_header(&mut file)
     }
 
-    //Will make a sequential read until it finds the register with the nseq given
-    pub fn sequential_read(&self, nseq: u32) -> Option<Registro> {
-        let mut file = self.get_file();
-        let mut buffer = [0u8; 100];
-        if nseq > self.file_size {
-            return None;
+    //Only the page of the register changes (and the page it was forwarded to), the registers
+    //of other pages never move
+    pub fn delete_by_rid(&mut self, rid: Rid) -> Result<()> {
+        let mut file = self.get_file()?;
+        let mut page = self.read_page(&mut file, rid.page)?;
+        if let Some(location) = page.forward(rid.slot) {
+            self.release_moved(&mut file, Rid::decode(location))?;
         }
-        loop {
-            match file.read(&mut buffer) {
-                Ok(0) => return None,
-                Ok(_) => {
-                    let (nseq_buf, nome_buf) = buffer.split_at(std::mem::size_of::<u32>());
-                    let registro_nseq = u32::from_be_bytes(nseq_buf.try_into().unwrap());
-                    if registro_nseq == nseq {
-                        let nome = String::from_utf8_lossy(nome_buf).to_string();
-                        let registro = Registro {
-                            nseq,
-                            nome: nome
-                                .chars()
-                                .take(96)
-                                .collect::<Vec<char>>()
-                                .try_into()
-                                .unwrap(),
-                        };
-                        return Some(registro); //Returns desired register
-                    }
-                }
-                Err(_) => return None, // read error
+        let had_free_slot = page.has_free_slot();
+        let deleted = match self.delete_mode {
+            DeleteMode::Immediate => page.delete(rid.slot),
+            DeleteMode::Tombstone => page.mark_deleted(rid.slot),
+        };
+        if !deleted {
+            return Err(Error::NotFound);
+        }
+        //The page gets its first free slot, so it joins the free list. Sorted files never
+        //fill holes, so they keep no list
+        if !had_free_slot && page.has_free_slot() && self.organization == Organization::Heap {
+            page.set_next_free(self.free_head);
+            self.free_head = rid.page;
+        }
+        self.write_page(&mut file, rid.page, &page)?;
+        self.file_size -= 1;
+        match self.delete_mode {
+            DeleteMode::Immediate => self.free_records += 1,
+            DeleteMode::Tombstone => self.tombstones += 1,
+        }
+        self.write_header(&mut file)
+    }
+
+    //Register stored for a forwarded slot, location is the rid kept in that slot
+    fn read_moved(&self, file: &mut File, location: &[u8]) -> Result<Registro> {
+        let location = Rid::decode(location);
+        let page = self.read_page(file, location.page)?;
+        match page.moved(location.slot) {
+            Some(record) => self.decode(location.page, record),
+            None => Err(Error::CorruptPage {
+                page: location.page as u64,
+                reason: "forward points to a slot without a moved register",
+            }),
+        }
+    }
+
+    //Frees the slot of a moved register, the caller writes the header
+    fn release_moved(&mut self, file: &mut File, location: Rid) -> Result<()> {
+        let mut page = self.read_page(file, location.page)?;
+        let had_free_slot = page.has_free_slot();
+        if !page.release_moved(location.slot) {
+            return Err(Error::CorruptPage {
+                page: location.page as u64,
+                reason: "forward points to a slot without a moved register",
+            });
+        }
+        if !had_free_slot {
+            page.set_next_free(self.free_head);
+            self.free_head = location.page;
+        }
+        self.free_records += 1;
+        self.write_page(file, location.page, &page)
+    }
+
+    //Frees the slots of every tombstone and compacts their pages, then truncates the empty
+    //pages at the end of the file. Registers never change page or slot, so rids stay valid.
+    //Pages are visited from the last one, which finds the truncation point on the way and
+    //rebuilds the free list in page order (sorted files keep none)
+    pub fn vacuum(&mut self) -> Result<VacuumReport> {
+        self.vacuum_as(self.organization)
+    }
+
+    //The vacuum that also turns the file into organization, which is only set once the pages
+    //are written
+    fn vacuum_as(&mut self, organization: Organization) -> Result<VacuumReport> {
+        let mut file = self.get_file()?;
+        let mut report = VacuumReport::default();
+        let mut page_count = self.page_count;
+        let mut free_head = NO_PAGE;
+        let mut free_records = 0;
+        for number in (0..self.page_count).rev() {
+            let mut page = self.read_page(&mut file, number)?;
+            let (tombstones, bytes) = page.purge();
+            report.tombstones += tombstones;
+            report.bytes_purged += bytes as u64;
+            if number + 1 == page_count && page.is_empty() {
+                page_count = number; //Cut with the file, no need to write it
+                continue;
+            }
+            let mut next_free = NO_PAGE;
+            free_records += page.free_slots();
+            if page.has_free_slot() && organization == Organization::Heap {
+                next_free = std::mem::replace(&mut free_head, number);
+            }
+            if tombstones > 0 || page.get_next_free() != next_free {
+                page.set_next_free(next_free);
+                self.write_page(&mut file, number, &page)?;
+            }
+        }
+
+        let old_length = self.get_file_size()?;
+        report.pages_truncated = self.page_count - page_count;
+        self.page_count = page_count;
+        self.organization = organization;
+        self.sorted_pages = match organization {
+            Organization::Heap => 0,
+            Organization::Sorted => self.sorted_pages.min(page_count),
+        };
+        file.set_len(self.page_position(page_count))?;
+        report.bytes_truncated = old_length - self.page_position(page_count);
+
+        self.free_head = free_head;
+        self.free_records = free_records;
+        self.tombstones = 0;
+        self.write_header(&mut file)?;
+        Ok(report)
+    }
+
+    pub fn set_delete_mode(&mut self, delete_mode: DeleteMode) -> Result<()> {
+        self.delete_mode = delete_mode;
+        let mut file = self.get_file()?;
+        self.write_header(&mut file)
+    }
+
+    pub fn get_delete_mode(&self) -> DeleteMode {
+        self.delete_mode
+    }
+
+    pub fn get_tombstones(&self) -> u32 {
+        self.tombstones
+    }
+
+    fn find(&self, nseq: u32) -> Result<(Rid, Registro)> {
+        if self.organization == Organization::Sorted {
+            return match self.search(nseq)?.results.into_iter().next() {
+                Some(found) => Ok(found),
+                None => Err(Error::NotFound),
+            };
+        }
+        for scanned in self.scan()? {
+            let (rid, registro) = scanned?;
+            if registro.get_nseq() == nseq {
+                return Ok((rid, registro)); //Returns desired register
             }
         }
+        Err(Error::NotFound)
+    }
+
+    //Every live register with its rid, reading one page at a time. A sorted file gives its
+    //sorted pages in nseq order and then its overflow area
+    pub fn scan(&self) -> Result<Scan<'_>> {
+        self.scan_pages(0..self.page_count)
+    }
+
+    fn scan_pages(&self, pages: Range<u32>) -> Result<Scan<'_>> {
+        Ok(Scan {
+            arquivo: self,
+            file: self.get_file()?,
+            page: None,
+            next_page: pages.start,
+            end_page: pages.end,
+            next_slot: 0,
+        })
+    }
+
+    pub fn get_number_of_registers(&self) -> u32 {
+        self.file_size
+    }
+
+    pub fn get_page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    //Every page read counts as one block, whether for a scan or a direct access
+    pub fn get_block_reads(&self) -> u64 {
+        self.block_reads.get()
+    }
+
+    pub fn reset_block_reads(&self) {
+        self.block_reads.set(0);
     }
 
     pub fn sequential_read_blocks(
         &self,
         mut nseq: u32,
         amount: usize,
-    ) -> Option<Vec<Option<Registro>>> {
-        let mut file = self.get_file();
-        let mut buffer = [0u8; 100];
+    ) -> Result<Vec<Option<Registro>>> {
+        let mut scan = self.scan()?;
         let mut registros: Vec<Option<Registro>> = Vec::new();
         for _i in 0..amount {
-            if nseq > self.file_size {
-                registros.push(None);
-            }
-            loop {
-                match file.read(&mut buffer) {
-                    Ok(0) => registros.push(None),
-                    Ok(_) => {
-                        let (nseq_buf, nome_buf) = buffer.split_at(std::mem::size_of::<u32>());
-                        let registro_nseq = u32::from_be_bytes(nseq_buf.try_into().unwrap());
-                        if registro_nseq == nseq {
-                            let nome = String::from_utf8_lossy(nome_buf).to_string();
-                            let registro = Registro {
-                                nseq,
-                                nome: nome
-                                    .chars()
-                                    .take(96)
-                                    .collect::<Vec<char>>()
-                                    .try_into()
-                                    .unwrap(),
-                            };
-                            registros.push(Some(registro));
+            //The scan goes on from where the previous nseq was found
+            let mut found = None;
+            for scanned in scan.by_ref() {
+                let (_, registro) = scanned?;
+                if registro.get_nseq() == nseq {
+                    found = Some(registro);
+                    break;
+                }
+            }
+            registros.push(found);
+            nseq += 1;
+        }
+        Ok(registros)
+    }
+
+    //Inserts a register with the next nseq and random values
+    pub fn insert_at_end(&mut self) -> Result<Rid> {
+        if self.next_nseq == u32::MAX {
+            return Err(Error::Full("register file already has u32::MAX registers"));
+        }
+        let values = self.schema.random_row(self.next_nseq);
+        self.insert(&Registro { values })
+    }
+
+    //Writes the register in a slot freed by a delete, in the first page of the free list with
+    //room for it, or else after the registers of the last page, or in a new page when it is full.
+    //In append only mode the free list is ignored. Returns where it went
+    pub fn insert(&mut self, registro: &Registro) -> Result<Rid> {
+        let record = self.schema.encode(&registro.values)?;
+        let mut file = self.get_file()?;
+        let rid = match self.organization {
+            Organization::Heap => self.store(&mut file, &record, false)?,
+            Organization::Sorted => self.insert_sorted(&mut file, registro.get_nseq(), &record)?,
+        };
+        self.file_size += 1;
+        self.next_nseq = self.next_nseq.max(registro.get_nseq().saturating_add(1));
+        self.write_header(&mut file)?;
+        Ok(rid)
+    }
+
+    //moved is for a register that grew out of its page, scans reach it through its forward
+    fn store(&mut self, file: &mut File, record: &[u8], moved: bool) -> Result<Rid> {
+        match self.insert_in_hole(file, record, moved)? {
+            Some(rid) => Ok(rid),
+            None => self.append(file, record, moved),
+        }
+    }
+
+    //The first page of the free list with room for the record. Pages whose free slot is too
+    //small for it (rows of VarStrings differ in length) are passed over, and the one that
+    //fills its last free slot leaves the list
+    fn insert_in_hole(
+        &mut self,
+        file: &mut File,
+        record: &[u8],
+        moved: bool,
+    ) -> Result<Option<Rid>> {
+        if self.append_only {
+            return Ok(None);
+        }
+        let mut previous: Option<(u32, Page)> = None;
+        let mut number = self.free_head;
+        let mut visited = 0;
+        while number != NO_PAGE {
+            visited += 1;
+            if visited > self.page_count {
+                return Err(Error::CorruptPage {
+                    page: number as u64,
+                    reason: "free list goes around in a cycle",
+                });
+            }
+            let mut page = self.read_page(file, number)?;
+            if !page.has_free_slot() {
+                return Err(Error::CorruptPage {
+                    page: number as u64,
+                    reason: "page in the free list has no free slot",
+                });
+            }
+            let inserted = if moved {
+                page.insert_moved(record)
+            } else {
+                page.insert(record)
+            };
+            let slot = match inserted {
+                Some(slot) => slot,
+                None => {
+                    let next = page.get_next_free();
+                    previous = Some((number, page));
+                    number = next;
+                    continue;
+                }
+            };
+            if !page.has_free_slot() {
+                let next = page.get_next_free();
+                page.set_next_free(NO_PAGE);
+                match previous {
+                    Some((previous_number, mut previous_page)) => {
+                        previous_page.set_next_free(next);
+                        self.write_page(file, previous_number, &previous_page)?;
+                    }
+                    None => self.free_head = next,
+                }
+            }
+            self.write_page(file, number, &page)?;
+            self.free_records -= 1;
+            return Ok(Some(Rid::new(number, slot)));
+        }
+        Ok(None)
+    }
+
+    //A new slot after every other one keeps the insertion order in scans
+    fn append(&mut self, file: &mut File, record: &[u8], moved: bool) -> Result<Rid> {
+        let append = |page: &mut Page| {
+            if moved {
+                page.append_moved(record)
+            } else {
+                page.append(record)
+            }
+        };
+        if self.page_count > 0 {
+            let number = self.page_count - 1;
+            let mut page = self.read_page(file, number)?;
+            if let Some(slot) = append(&mut page) {
+                self.write_page(file, number, &page)?;
+                return Ok(Rid::new(number, slot));
+            }
+        }
+        let number = self.page_count;
+        let mut page = Page::new(self.page_size() as usize);
+        let slot = match append(&mut page) {
+            Some(slot) => slot,
+            None => return Err(Error::Full("register does not fit in an empty page")),
+        };
+        self.write_page(file, number, &page)?;
+        self.page_count += 1;
+        Ok(Rid::new(number, slot))
+    }
+
+    //Point lookup, see search_range
+    pub fn search(&self, nseq: u32) -> Result<Search> {
+        self.search_range(nseq, nseq)
+    }
+
+    //Registers with from <= nseq <= to in nseq order, and the blocks read to find them. A
+    //sorted file is binary searched for the first page of the range and read from there on,
+    //plus its whole overflow area. A heap file is read entirely
+    pub fn search_range(&self, from: u32, to: u32) -> Result<Search> {
+        let reads_before = self.block_reads.get();
+        let mut results = Vec::new();
+        let unsorted = match self.organization {
+            Organization::Heap => 0..self.page_count,
+            Organization::Sorted => self.sorted_pages..self.page_count,
+        };
+        if self.organization == Organization::Sorted {
+            let mut file = self.get_file()?;
+            if let Some((mut number, mut page)) = self.lower_bound(&mut file, from)? {
+                loop {
+                    let mut past_range = false;
+                    for (slot, record) in page.records() {
+                        let key = key_of(number, record)?;
+                        if key > to {
+                            past_range = true;
                             break;
                         }
+                        if key >= from {
+                            results.push((Rid::new(number, slot), self.decode(number, record)?));
+                        }
+                    }
+                    number += 1;
+                    if past_range || number >= self.sorted_pages {
+                        break;
                     }
-                    Err(_) => registros.push(None),
+                    page = self.read_page(&mut file, number)?;
                 }
             }
-            nseq += 1;
         }
-        Some(registros)
+        for scanned in self.scan_pages(unsorted)? {
+            let (rid, registro) = scanned?;
+            if (from..=to).contains(&registro.get_nseq()) {
+                results.push((rid, registro));
+            }
+        }
+        results.sort_by_key(|(_, registro)| registro.get_nseq());
+        Ok(Search {
+            results,
+            block_reads: self.block_reads.get() - reads_before,
+        })
     }
 
-    pub fn insert_at_end(&mut self) {
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&self.file_name)
-            .unwrap();
-        let registro = Registro::new(self.file_size);
-        let mut nseq = registro.nseq.to_be_bytes().to_vec();
-        let mut nome = registro.nome.iter().collect::<String>().into_bytes();
-        let mut vec = Vec::new();
-        vec.append(&mut nseq);
-        vec.append(&mut nome);
-        self.file_size += 1;
-        file.write_all(&vec).unwrap();
+    //First sorted page whose last nseq is not below key, with the page already read. Empty
+    //pages have no nseq to compare, the first page with registers after them decides
+    fn lower_bound(&self, file: &mut File, key: u32) -> Result<Option<(u32, Page)>> {
+        let (mut low, mut high) = (0, self.sorted_pages);
+        let mut found = None;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let mut probe = middle;
+            let mut page = self.read_page(file, probe)?;
+            while page.records().next().is_none() && probe + 1 < high {
+                probe += 1;
+                page = self.read_page(file, probe)?;
+            }
+            let last = match page.records().last() {
+                Some((_, record)) => Some(key_of(probe, record)?),
+                None => None,
+            };
+            match last {
+                None => high = middle,
+                Some(last) if last < key => low = probe + 1,
+                Some(_) => {
+                    high = middle;
+                    found = Some((probe, page));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    //The register goes in order into its sorted page when the page can take it, or else to
+    //the overflow area, which is merged first if it already has max_overflow_pages pages
+    fn insert_sorted(&mut self, file: &mut File, key: u32, record: &[u8]) -> Result<Rid> {
+        if self.sorted_pages > 0 {
+            let (number, page) = match self.lower_bound(file, key)? {
+                Some(found) => found,
+                None => {
+                    let last = self.sorted_pages - 1;
+                    (last, self.read_page(file, last)?)
+                }
+            };
+            if let Some((sorted, slot)) =
+                with_record_in_order(&page, number, key, record, self.page_size())?
+            {
+                self.write_page(file, number, &sorted)?;
+                self.tombstones -= page.tombstones();
+                self.free_records -= page.free_slots();
+                return Ok(Rid::new(number, slot));
+            }
+        }
+
+        if self.page_count > self.sorted_pages {
+            let last = self.page_count - 1;
+            let mut page = self.read_page(file, last)?;
+            if let Some(slot) = page.append(record) {
+                self.write_page(file, last, &page)?;
+                return Ok(Rid::new(last, slot));
+            }
+        }
+        let overflow_pages = self.page_count - self.sorted_pages;
+        if overflow_pages > 0 && overflow_pages >= self.max_overflow_pages {
+            *file = self.merge()?;
+            return self.insert_sorted(file, key, record);
+        }
+        let number = self.page_count;
+        let mut page = Page::new(self.page_size() as usize);
+        let slot = match page.append(record) {
+            Some(slot) => slot,
+            None => return Err(Error::Full("register does not fit in an empty page")),
+        };
+        self.write_page(file, number, &page)?;
+        self.page_count += 1;
+        Ok(Rid::new(number, slot))
+    }
+
+    //In place when the page can take the new length, or else the register leaves its page
+    //and is inserted again. Its nseq cannot change, it decides where the register is
+    fn update_sorted(&mut self, rid: Rid, key: u32, record: &[u8]) -> Result<()> {
+        let mut file = self.get_file()?;
+        let mut page = self.read_page(&mut file, rid.page)?;
+        match page.record(rid.slot) {
+            Some(old) if key_of(rid.page, old)? == key => {}
+            Some(_) => {
+                return Err(Error::InvalidRow(
+                    "the nseq of a register in a sorted file cannot change".to_owned(),
+                ))
+            }
+            None => return Err(Error::NotFound),
+        }
+        if page.can_hold(rid.slot, record.len()) {
+            page.set_record(rid.slot, record);
+            return self.write_page(&mut file, rid.page, &page);
+        }
+        //A merge while inserting it again counts the registers in the file, so the count
+        //must not have it by then
+        let old = page.record(rid.slot).unwrap().to_vec();
+        page.delete(rid.slot);
+        self.write_page(&mut file, rid.page, &page)?;
+        self.free_records += 1;
+        self.file_size -= 1;
+        let inserted = self.insert_sorted(&mut file, key, record);
+        //When it cannot go in, the old register goes back (its rid may change)
+        let restored = match inserted {
+            Ok(_) => Ok(()),
+            Err(_) => self.insert_sorted(&mut file, key, &old).map(|_| ()),
+        };
+        if restored.is_ok() {
+            self.file_size += 1;
+        }
+        self.write_header(&mut file)?;
+        inserted.and(restored)
     }
 
-    pub fn update_random(&self, nseq: u32) {
-        let register = Registro::new(nseq);
-        let mut file = self.get_file();
-        let pos = (nseq as usize) * 100;
-        file.seek(SeekFrom::Start(pos as u64)).unwrap();
-        let mut nseq = register.nseq.to_be_bytes().to_vec();
-        let mut nome = register.nome.iter().collect::<String>().into_bytes();
-        let mut vec = Vec::new();
-        vec.append(&mut nseq);
-        vec.append(&mut nome);
-        file.write_all(&vec).unwrap();
+    //Rewrites the file with every register in nseq order, buffer_size registers per page so
+    //later inserts find room in their page. The overflow area is sorted in memory and merged
+    //with the sorted pages as both are read. Returns the file, which is a new one
+    fn merge(&mut self) -> Result<File> {
+        let temporary_name = format!("{}.merge", self.file_name);
+        match self.write_merged(&temporary_name) {
+            Ok((mut temporary, page_count, file_size)) => {
+                self.replace_with(&mut temporary, &temporary_name, page_count, file_size)
+            }
+            Err(error) => {
+                let _ = std::fs::remove_file(&temporary_name);
+                Err(error)
+            }
+        }
     }
 
-    pub fn delete_register(&mut self, nseq: u32) -> bool {
-        let mut file = self.get_file();
-        let mut buffer = [0u8; 100];
-        let mut read_pos = 0;
-        let mut write_pos = 0;
-        let mut found = false;
+    //The merged file at temporary_name, with its page and register counts
+    fn write_merged(&self, temporary_name: &str) -> Result<(File, u32, u32)> {
+        let mut overflow = Vec::new();
+        for scanned in self.scan_pages(self.sorted_pages..self.page_count)? {
+            let registro = scanned?.1;
+            overflow.push((registro.get_nseq(), self.schema.encode(&registro.values)?));
+        }
+        overflow.sort_by_key(|(key, _)| *key);
+        let mut overflow = overflow.into_iter().peekable();
+        let mut sorted = self.scan_pages(0..self.sorted_pages)?.peekable();
 
+        let mut temporary = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(temporary_name)?;
+        temporary.write_all(&self.header_bytes())?;
+        let mut writer = PageWriter::new(temporary, self.page_size(), self.buffer_size);
         loop {
-            let read_result = file.read(&mut buffer);
-            match read_result {
-                Ok(0) => break,
-                Ok(_) => {
-                    let (nseq_buf, _) = buffer.split_at(std::mem::size_of::<u32>());
-                    let registro_nseq = u32::from_be_bytes(nseq_buf.try_into().unwrap());
-                    if registro_nseq != nseq {
-                        if read_pos != write_pos {
-                            file.seek(SeekFrom::Start(write_pos)).unwrap();
-                            file.write_all(&buffer).unwrap();
-                        }
-                        write_pos += 100;
-                    } else {
-                        found = true;
+            //Equal nseqs keep the register of the sorted pages first
+            let take_sorted = match (sorted.peek(), overflow.peek()) {
+                (None, None) => break,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(Err(_)), _) => true,
+                (Some(Ok((_, registro))), Some((key, _))) => registro.get_nseq() <= *key,
+            };
+            let record = if take_sorted {
+                self.schema.encode(&sorted.next().unwrap()?.1.values)?
+            } else {
+                overflow.next().unwrap().1
+            };
+            writer.push(&record)?;
+        }
+        drop(sorted);
+        writer.finish()
+    }
+
+    //The file becomes the sorted one at temporary_name, with page_count pages of file_size
+    //registers in order and no holes. If that fails the Arquivo stays as it was
+    fn replace_with(
+        &mut self,
+        temporary: &mut File,
+        temporary_name: &str,
+        page_count: u32,
+        file_size: u32,
+    ) -> Result<File> {
+        let before = (
+            self.organization,
+            self.page_count,
+            self.sorted_pages,
+            self.file_size,
+            self.free_records,
+            self.free_head,
+            self.tombstones,
+        );
+        self.organization = Organization::Sorted;
+        self.page_count = page_count;
+        self.sorted_pages = page_count;
+        self.file_size = file_size;
+        self.free_records = 0;
+        self.free_head = NO_PAGE;
+        self.tombstones = 0;
+        let replaced = self
+            .write_header(temporary)
+            .and_then(|_| Ok(std::fs::rename(temporary_name, &self.file_name)?));
+        if let Err(error) = replaced {
+            (
+                self.organization,
+                self.page_count,
+                self.sorted_pages,
+                self.file_size,
+                self.free_records,
+                self.free_head,
+                self.tombstones,
+            ) = before;
+            let _ = std::fs::remove_file(temporary_name);
+            return Err(error);
+        }
+        self.get_file()
+    }
+
+    //Turning a heap file into a sorted one sorts it by nseq with sort_by. Turning it back into
+    //a heap keeps the order and rebuilds the free list with a vacuum
+    pub fn set_organization(&mut self, organization: Organization) -> Result<()> {
+        if organization == self.organization {
+            return Ok(());
+        }
+        match organization {
+            Organization::Sorted => {
+                let temporary_name = format!("{}.sort", self.file_name);
+                let (sorted, _) = self.sort_by(
+                    self.schema.get_columns()[0].get_name(),
+                    temporary_name.clone(),
+                )?;
+                let mut temporary = match sorted.get_file() {
+                    Ok(temporary) => temporary,
+                    Err(error) => {
+                        let _ = std::fs::remove_file(&temporary_name);
+                        return Err(error);
                     }
-                    read_pos += 100;
+                };
+                self.replace_with(
+                    &mut temporary,
+                    &temporary_name,
+                    sorted.page_count,
+                    sorted.file_size,
+                )
+                .map(|_| ())
+            }
+            Organization::Heap => self.vacuum_as(Organization::Heap).map(|_| ()),
+        }
+    }
+
+    //Copy of the file in file_name with its registers ordered by column (equal values keep the
+    //order of the file). The first pass sorts buffer_size registers at a time into runs, each
+    //further pass merges buffer_size - 1 runs (at least two) holding one register of each,
+    //and the last one writes the copy. Runs are read and written a register at a time, the
+    //file and the copy a page at a time, so besides those registers one page is in memory:
+    //the page of the file being read or the page of the copy being written. The report has
+    //the bound in bytes. A copy by nseq is a sorted file
+    pub fn sort_by(&self, column: &str, file_name: String) -> Result<(Arquivo, SortReport)> {
+        let index = match self.schema.column_index(column) {
+            Some(index) => index,
+            None => return Err(Error::InvalidRow(format!("no column named {}", column))),
+        };
+        let reads_before = self.block_reads.get();
+        let mut report = SortReport::default();
+        let mut sorted =
+            Arquivo::new_with_schema(0, self.buffer_size, file_name, self.schema.clone());
+        sorted.next_nseq = self.next_nseq;
+        if index == 0 {
+            sorted.organization = Organization::Sorted;
+        }
+        let key = |record: &[u8]| -> Result<Value> {
+            Ok(self.schema.decode(0, record)?.swap_remove(index))
+        };
+        //The copy only stays if the sort gets to the end
+        let mut temporary = TemporaryFiles(vec![sorted.file_name.clone()]);
+
+        //First pass, a file that fits in memory goes straight to the copy
+        let memory = self.buffer_size.max(1) as usize;
+        let mut runs = Vec::new();
+        let mut chunk = Vec::with_capacity(memory);
+        let run_name = |temporary: &mut TemporaryFiles| {
+            let name = format!("{}.run{}", sorted.file_name, temporary.0.len());
+            temporary.0.push(name.clone());
+            name
+        };
+        for scanned in self.scan()? {
+            let registro = scanned?.1;
+            if chunk.len() == memory {
+                let run = write_run(&mut chunk, run_name(&mut temporary))?;
+                report.run_writes += run.records;
+                runs.push(run);
+            }
+            chunk.push((
+                registro.values[index].clone(),
+                self.schema.encode(&registro.values)?,
+            ));
+        }
+        report.passes = 1;
+        report.memory_registers = memory as u64;
+        if runs.is_empty() {
+            chunk.sort_by(|(a, _), (b, _)| a.compare(b));
+            let mut writer = sorted.copy_writer()?;
+            for (_, record) in &chunk {
+                writer.push(record)?;
+            }
+            report.page_writes += sorted.finish_copy(writer)? as u64;
+        } else {
+            if !chunk.is_empty() {
+                let run = write_run(&mut chunk, run_name(&mut temporary))?;
+                report.run_writes += run.records;
+                runs.push(run);
+            }
+            report.runs = runs.len() as u32;
+            let fan_in = self.buffer_size.saturating_sub(1).max(2) as usize;
+            report.memory_registers = report.memory_registers.max(fan_in as u64);
+            while runs.len() > fan_in {
+                let mut merged = Vec::new();
+                for group in runs.chunks(fan_in) {
+                    let mut writer = RunWriter::create(run_name(&mut temporary))?;
+                    report.run_reads += merge_runs(group, &key, |record| writer.push(record))?;
+                    let run = writer.finish();
+                    report.run_writes += run.records;
+                    merged.push(run);
                 }
-                Err(_) => return false, // read error
+                runs = merged;
+                report.passes += 1;
             }
+            let mut writer = sorted.copy_writer()?;
+            report.run_reads += merge_runs(&runs, &key, |record| writer.push(record))?;
+            report.page_writes += sorted.finish_copy(writer)? as u64;
+            report.passes += 1;
         }
-        match file.flush() {
-            Ok(_) => found,
-            Err(_) => false,
+        report.page_reads += self.block_reads.get() - reads_before;
+        report.memory_bytes =
+            report.memory_registers * self.schema.max_row_size() as u64 + self.page_size();
+        temporary.0.remove(0);
+        Ok((sorted, report))
+    }
+
+    //Writer of the pages of an empty copy, after its header
+    fn copy_writer(&self) -> Result<PageWriter> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_name)?;
+        file.write_all(&self.header_bytes())?;
+        Ok(PageWriter::new(file, self.page_size(), self.buffer_size))
+    }
+
+    //Pages of the copy
+    fn finish_copy(&mut self, writer: PageWriter) -> Result<u32> {
+        let (mut file, page_count, file_size) = writer.finish()?;
+        self.page_count = page_count;
+        self.file_size = file_size;
+        if self.organization == Organization::Sorted {
+            self.sorted_pages = page_count;
+        }
+        self.write_header(&mut file)?;
+        Ok(page_count)
+    }
+
+    pub fn get_organization(&self) -> Organization {
+        self.organization
+    }
+
+    pub fn set_max_overflow_pages(&mut self, max_overflow_pages: u32) -> Result<()> {
+        self.max_overflow_pages = max_overflow_pages;
+        let mut file = self.get_file()?;
+        self.write_header(&mut file)
+    }
+
+    pub fn get_max_overflow_pages(&self) -> u32 {
+        self.max_overflow_pages
+    }
+
+    pub fn get_sorted_pages(&self) -> u32 {
+        self.sorted_pages
+    }
+
+    pub fn get_overflow_pages(&self) -> u32 {
+        self.page_count - self.sorted_pages
+    }
+
+    pub fn set_append_only(&mut self, append_only: bool) -> Result<()> {
+        self.append_only = append_only;
+        let mut file = self.get_file()?;
+        self.write_header(&mut file)
+    }
+
+    pub fn is_append_only(&self) -> bool {
+        self.append_only
+    }
+
+    pub fn get_free_records(&self) -> u32 {
+        self.free_records
+    }
+
+    //The register with this nseq gets new random values, except for the nseq
+    pub fn update_random(&mut self, nseq: u32) -> Result<()> {
+        let rid = self.locate(nseq)?;
+        let values = self.schema.random_row(nseq);
+        self.update_by_rid(rid, &Registro { values })
+    }
+
+    pub fn get_schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn decode(&self, page: u32, record: &[u8]) -> Result<Registro> {
+        let values = self.schema.decode(page as u64, record)?;
+        Ok(Registro { values })
+    }
+
+    pub fn delete_register(&mut self, nseq: u32) -> Result<()> {
+        let rid = self.locate(nseq)?;
+        self.delete_by_rid(rid)
+    }
+
+    //A page has room for buffer_size registers of the longest size with their slots
+    fn page_size(&self) -> u64 {
+        let room = self.schema.max_row_size().max(MIN_RECORD_ROOM);
+        (PAGE_HEADER_SIZE + self.buffer_size.max(1) as usize * (SLOT_ENTRY_SIZE + room)) as u64
+    }
+
+    //Pages start after the header and the schema
+    fn page_position(&self, page: u32) -> u64 {
+        HEADER_SIZE + self.schema.to_bytes().len() as u64 + page as u64 * self.page_size()
+    }
+
+    fn read_page(&self, file: &mut File, page: u32) -> Result<Page> {
+        if page >= self.page_count {
+            return Err(Error::NotFound);
         }
+        let mut data = vec![0u8; self.page_size() as usize];
+        file.seek(SeekFrom::Start(self.page_position(page)))?;
+        file.read_exact(&mut data)?;
+        self.block_reads.set(self.block_reads.get() + 1);
+        Page::from_bytes(page as u64, data)
+    }
+
+    fn write_page(&self, file: &mut File, page: u32, contents: &Page) -> Result<()> {
+        file.seek(SeekFrom::Start(self.page_position(page)))?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
     }
 
-    pub fn get_file_size(&self) -> u64 {
+    pub fn get_file_size(&self) -> Result<u64> {
         let path = Path::new(&self.file_name);
-        let metadata = std::fs::metadata(path).unwrap();
-        metadata.len()
+        let metadata = std::fs::metadata(path)?;
+        Ok(metadata.len())
     }
 
-    pub fn get_file(&self) -> File {
+    pub fn get_file(&self) -> Result<File> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(&self.file_name)
-            .unwrap();
-        file
+            .truncate(false)
+            .open(&self.file_name)?;
+        Ok(file)
+    }
+
+    fn header_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HEADER_SIZE as usize);
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&(self.schema.max_row_size() as u32).to_be_bytes());
+        buffer.extend_from_slice(&self.buffer_size.to_be_bytes());
+        buffer.extend_from_slice(&self.file_size.to_be_bytes());
+        buffer.extend_from_slice(&self.free_records.to_be_bytes());
+        buffer.extend_from_slice(&self.page_count.to_be_bytes());
+        buffer.extend_from_slice(&(self.page_size() as u32).to_be_bytes());
+        buffer.extend_from_slice(&self.next_nseq.to_be_bytes());
+        buffer.extend_from_slice(&self.tombstones.to_be_bytes());
+        buffer.push(self.delete_mode.to_header());
+        buffer.extend_from_slice(&self.free_head.to_be_bytes());
+        buffer.push(self.append_only as u8);
+        let schema = self.schema.to_bytes();
+        buffer.extend_from_slice(&(schema.len() as u16).to_be_bytes());
+        buffer.push(self.organization.to_header());
+        buffer.extend_from_slice(&self.sorted_pages.to_be_bytes());
+        buffer.extend_from_slice(&self.max_overflow_pages.to_be_bytes());
+        buffer.resize(HEADER_SIZE as usize, 0);
+        buffer.extend_from_slice(&schema);
+        buffer
+    }
+
+    fn write_header(&self, file: &mut File) -> Result<()> {
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.header_bytes())?;
+        Ok(())
+    }
+}
+
+//Sequential scan of an Arquivo, one page in memory at a time
+pub struct Scan<'a> {
+    arquivo: &'a Arquivo,
+    file: File,
+    page: Option<Page>,
+    next_page: u32,
+    end_page: u32,
+    next_slot: u32,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Rid, Registro)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(page) = &self.page {
+                let page_number = self.next_page - 1;
+                while self.next_slot < page.slot_count() {
+                    let slot = self.next_slot;
+                    self.next_slot += 1;
+                    let registro = match (page.record(slot), page.forward(slot)) {
+                        (Some(record), _) => self.arquivo.decode(page_number, record),
+                        (None, Some(location)) => self.arquivo.read_moved(&mut self.file, location),
+                        _ => continue,
+                    };
+                    return Some(registro.map(|registro| (Rid::new(page_number, slot), registro)));
+                }
+            }
+            if self.next_page >= self.end_page {
+                return None;
+            }
+            match self.arquivo.read_page(&mut self.file, self.next_page) {
+                Ok(page) => self.page = Some(page),
+                Err(error) => {
+                    self.next_page = self.end_page;
+                    self.page = None;
+                    return Some(Err(error));
+                }
+            }
+            self.next_page += 1;
+            self.next_slot = 0;
+        }
+    }
+}
+
+//What a vacuum gave back: space inside the pages, ready for new registers, and whole pages
+//cut from the end of the file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VacuumReport {
+    tombstones: u32,
+    bytes_purged: u64,
+    pages_truncated: u32,
+    bytes_truncated: u64,
+}
+
+#[allow(dead_code)]
+impl VacuumReport {
+    pub fn get_tombstones(&self) -> u32 {
+        self.tombstones
+    }
+
+    pub fn get_bytes_purged(&self) -> u64 {
+        self.bytes_purged
+    }
+
+    pub fn get_pages_truncated(&self) -> u32 {
+        self.pages_truncated
+    }
+
+    pub fn get_bytes_truncated(&self) -> u64 {
+        self.bytes_truncated
+    }
+
+    pub fn get_bytes_reclaimed(&self) -> u64 {
+        self.bytes_purged + self.bytes_truncated
+    }
+}
+
+impl fmt::Display for VacuumReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tombstones purged: {} ({} bytes), pages truncated: {} ({} bytes), reclaimed: {} bytes",
+            self.tombstones,
+            self.bytes_purged,
+            self.pages_truncated,
+            self.bytes_truncated,
+            self.get_bytes_reclaimed()
+        )
+    }
+}
+
+//What a sort_by cost: the runs of its first pass, its passes over the registers (the first
+//one included), the pages of the file read and of the copy written, the registers read and
+//written in runs, and the most memory it used: registers sorted or merged at once, and the
+//bytes they and the page being read or written can take
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SortReport {
+    runs: u32,
+    passes: u32,
+    memory_registers: u64,
+    memory_bytes: u64,
+    page_reads: u64,
+    page_writes: u64,
+    run_reads: u64,
+    run_writes: u64,
+}
+
+#[allow(dead_code)]
+impl SortReport {
+    pub fn get_runs(&self) -> u32 {
+        self.runs
+    }
+
+    pub fn get_passes(&self) -> u32 {
+        self.passes
+    }
+
+    pub fn get_memory_registers(&self) -> u64 {
+        self.memory_registers
+    }
+
+    pub fn get_memory_bytes(&self) -> u64 {
+        self.memory_bytes
+    }
+
+    pub fn get_page_reads(&self) -> u64 {
+        self.page_reads
+    }
+
+    pub fn get_page_writes(&self) -> u64 {
+        self.page_writes
+    }
+
+    pub fn get_run_reads(&self) -> u64 {
+        self.run_reads
+    }
+
+    pub fn get_run_writes(&self) -> u64 {
+        self.run_writes
+    }
+}
+
+impl fmt::Display for SortReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Runs: {}, passes: {}, pages read: {}, pages written: {}, run registers read: {}, run registers written: {}, memory: {} registers and a page ({} bytes)",
+            self.runs,
+            self.passes,
+            self.page_reads,
+            self.page_writes,
+            self.run_reads,
+            self.run_writes,
+            self.memory_registers,
+            self.memory_bytes
+        )
+    }
+}
+
+//Files of a sort_by that go away when it ends, whether it worked or not
+struct TemporaryFiles(Vec<String>);
+
+impl Drop for TemporaryFiles {
+    fn drop(&mut self) {
+        for name in &self.0 {
+            let _ = std::fs::remove_file(name);
+        }
+    }
+}
+
+//Temporary file of a sort_by with registers in order, each one after its length (u16)
+struct Run {
+    name: String,
+    records: u64,
+}
+
+//A run of the first pass: the registers in memory sorted and written to their own file
+fn write_run(chunk: &mut Vec<(Value, Vec<u8>)>, name: String) -> Result<Run> {
+    chunk.sort_by(|(a, _), (b, _)| a.compare(b));
+    let mut writer = RunWriter::create(name)?;
+    for (_, record) in chunk.drain(..) {
+        writer.push(&record)?;
+    }
+    Ok(writer.finish())
+}
+
+//Merges the runs into push holding one register of each, then deletes them. Equal values
+//are taken from the earlier run, so the sort is stable. Returns the registers read
+fn merge_runs(
+    runs: &[Run],
+    key: &dyn Fn(&[u8]) -> Result<Value>,
+    mut push: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<u64> {
+    let mut readers = Vec::with_capacity(runs.len());
+    let mut heads = Vec::with_capacity(runs.len());
+    for run in runs {
+        let mut reader = RunReader::open(run)?;
+        heads.push(match reader.next()? {
+            Some(record) => Some((key(&record)?, record)),
+            None => None,
+        });
+        readers.push(reader);
+    }
+    loop {
+        let mut smallest: Option<usize> = None;
+        for (run, head) in heads.iter().enumerate() {
+            if let Some((value, _)) = head {
+                let smaller = match smallest.and_then(|other| heads[other].as_ref()) {
+                    Some((other, _)) => value.compare(other) == Ordering::Less,
+                    None => true,
+                };
+                if smaller {
+                    smallest = Some(run);
+                }
+            }
+        }
+        let run = match smallest {
+            Some(run) => run,
+            None => break,
+        };
+        let next = match readers[run].next()? {
+            Some(record) => Some((key(&record)?, record)),
+            None => None,
+        };
+        let (_, record) = std::mem::replace(&mut heads[run], next).unwrap();
+        push(&record)?;
+    }
+    for run in runs {
+        std::fs::remove_file(&run.name)?;
+    }
+    Ok(runs.iter().map(|run| run.records).sum())
+}
+
+//Writes a run one register at a time
+struct RunWriter {
+    file: File,
+    name: String,
+    records: u64,
+}
+
+impl RunWriter {
+    fn create(name: String) -> Result<RunWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&name)?;
+        Ok(RunWriter {
+            file,
+            name,
+            records: 0,
+        })
+    }
+
+    fn push(&mut self, record: &[u8]) -> Result<()> {
+        let mut buffer = Vec::with_capacity(2 + record.len());
+        buffer.extend_from_slice(&(record.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(record);
+        self.file.write_all(&buffer)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Run {
+        Run {
+            name: self.name,
+            records: self.records,
+        }
+    }
+}
+
+//Reads a run back one register at a time
+struct RunReader {
+    file: File,
+    records_left: u64,
+}
+
+impl RunReader {
+    fn open(run: &Run) -> Result<RunReader> {
+        Ok(RunReader {
+            file: File::open(&run.name)?,
+            records_left: run.records,
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.records_left == 0 {
+            return Ok(None);
+        }
+        let mut length = [0u8; 2];
+        self.file.read_exact(&mut length)?;
+        let mut record = vec![0u8; u16::from_be_bytes(length) as usize];
+        self.file.read_exact(&mut record)?;
+        self.records_left -= 1;
+        Ok(Some(record))
+    }
+}
+
+//Writes registers one page at a time from where the file is, buffer_size registers per page
+struct PageWriter {
+    file: File,
+    page: Page,
+    page_size: u64,
+    buffer_size: u32,
+    pages: u32,
+    records: u32,
+}
+
+impl PageWriter {
+    fn new(file: File, page_size: u64, buffer_size: u32) -> PageWriter {
+        PageWriter {
+            file,
+            page: Page::new(page_size as usize),
+            page_size,
+            buffer_size,
+            pages: 0,
+            records: 0,
+        }
+    }
+
+    fn push(&mut self, record: &[u8]) -> Result<()> {
+        if self.page.slot_count() >= self.buffer_size || !self.page.fits(record.len()) {
+            self.flush()?;
+        }
+        if self.page.append(record).is_none() {
+            return Err(Error::Full("register does not fit in an empty page"));
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.page.slot_count() > 0 {
+            self.file.write_all(self.page.as_bytes())?;
+            self.pages += 1;
+            self.page = Page::new(self.page_size as usize);
+        }
+        Ok(())
+    }
+
+    //The file with every page written, and how many pages and registers it got
+    fn finish(mut self) -> Result<(File, u32, u32)> {
+        self.flush()?;
+        Ok((self.file, self.pages, self.records))
+    }
+}
+
+//Registers found by search or search_range with their rids, and what finding them cost
+#[derive(Debug)]
+pub struct Search {
+    results: Vec<(Rid, Registro)>,
+    block_reads: u64,
+}
+
+#[allow(dead_code)]
+impl Search {
+    pub fn get_results(&self) -> &[(Rid, Registro)] {
+        &self.results
+    }
+
+    //The first one found, the only one of a point lookup on unique nseqs
+    pub fn get_registro(&self) -> Option<&Registro> {
+        self.results.first().map(|(_, registro)| registro)
+    }
+
+    pub fn get_block_reads(&self) -> u64 {
+        self.block_reads
+    }
+}
+
+//nseq of a register stored in the page, the first column of every schema
+fn key_of(page: u32, record: &[u8]) -> Result<u32> {
+    match record.get(0..4) {
+        Some(nseq) => Ok(u32::from_be_bytes(nseq.try_into().unwrap())),
+        None => Err(Error::CorruptPage {
+            page: page as u64,
+            reason: "register is shorter than its nseq",
+        }),
+    }
+}
+
+//A copy of page number with the record of nseq key among the others in nseq order, after any
+//with the same nseq, and its slot. Free slots and tombstones are left out. None if they do
+//not all fit
+fn with_record_in_order(
+    page: &Page,
+    number: u32,
+    key: u32,
+    record: &[u8],
+    page_size: u64,
+) -> Result<Option<(Page, u32)>> {
+    let mut sorted = Page::new(page_size as usize);
+    let mut slot = None;
+    for (_, other) in page.records() {
+        if slot.is_none() && key_of(number, other)? > key {
+            slot = sorted.append(record);
+            if slot.is_none() {
+                return Ok(None);
+            }
+        }
+        if sorted.append(other).is_none() {
+            return Ok(None);
+        }
+    }
+    if slot.is_none() {
+        slot = sorted.append(record);
+    }
+    Ok(slot.map(|slot| (sorted, slot)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{Column, ColumnType};
+
+    fn registro(schema: &Schema, nseq: u32, nome: &str) -> Registro {
+        Registro::from_values(
+            schema,
+            vec![Value::UInt32(nseq), Value::Text(nome.to_owned())],
+        )
+        .unwrap()
+    }
+
+    //Registers that grow out of their page while the overflow area is full go through a merge
+    //and must still be counted, in the Arquivo and in its header
+    #[test]
+    fn sorted_update_through_merge_keeps_the_count() {
+        let file_name = std::env::temp_dir()
+            .join(format!("t02_sorted_update_{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let schema = Schema::new(vec![
+            Column::new("nseq", ColumnType::UInt32),
+            Column::new("nome", ColumnType::VarString(20)),
+        ])
+        .unwrap();
+        let arquivo = Arquivo::new_with_schema(0, 2, file_name.clone(), schema.clone());
+        arquivo.write_in_file().unwrap();
+        let mut arquivo = Arquivo::open(file_name.clone()).unwrap();
+        arquivo.set_organization(Organization::Sorted).unwrap();
+        arquivo.set_max_overflow_pages(1).unwrap();
+
+        for nseq in 0..7 {
+            arquivo.insert(&registro(&schema, nseq, "")).unwrap();
+        }
+        for nseq in 0..7 {
+            let rid = arquivo.locate(nseq).unwrap();
+            let longo = registro(&schema, nseq, &"n".repeat(20));
+            arquivo.update_by_rid(rid, &longo).unwrap();
+            assert_eq!(arquivo.get_number_of_registers(), 7);
+            assert_eq!(arquivo.scan().unwrap().count(), 7);
+        }
+
+        let reopened = Arquivo::open(file_name.clone()).unwrap();
+        assert_eq!(reopened.get_number_of_registers(), 7);
+        let nseqs: Vec<u32> = reopened
+            .search_range(0, u32::MAX)
+            .unwrap()
+            .get_results()
+            .iter()
+            .map(|(_, registro)| registro.get_nseq())
+            .collect();
+        assert_eq!(nseqs, (0..7).collect::<Vec<_>>());
+        for nseq in 0..7 {
+            let nome = reopened.sequential_read(nseq).unwrap().get_values()[1].clone();
+            assert_eq!(nome, Value::Text("n".repeat(20)));
+        }
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    //Holes in later pages of the free list are filled when the first one is too small
+    #[test]
+    fn insert_fills_a_hole_past_the_head_of_the_free_list() {
+        let file_name = std::env::temp_dir()
+            .join(format!("t02_free_list_walk_{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let schema = Schema::new(vec![
+            Column::new("nseq", ColumnType::UInt32),
+            Column::new("nome", ColumnType::VarString(60)),
+        ])
+        .unwrap();
+        let arquivo = Arquivo::new_with_schema(0, 4, file_name.clone(), schema.clone());
+        arquivo.write_in_file().unwrap();
+        let mut arquivo = Arquivo::open(file_name.clone()).unwrap();
+        let longo = "n".repeat(60);
+        //Page 0: three long and four short registers, pages 1 and 2: four long ones each
+        for nseq in 0..15 {
+            let nome = if (3..7).contains(&nseq) { "" } else { &longo };
+            arquivo.insert(&registro(&schema, nseq, nome)).unwrap();
+        }
+        assert_eq!(arquivo.get_page_count(), 3);
+        arquivo.delete_register(12).unwrap();
+        arquivo.delete_register(4).unwrap();
+
+        //The head of the list (page 0) only has room for a short register
+        let rid = arquivo.insert(&registro(&schema, 100, &longo)).unwrap();
+        assert_eq!(rid.page, 2);
+        assert_eq!(arquivo.get_free_records(), 1);
+        let rid = arquivo.insert(&registro(&schema, 101, "")).unwrap();
+        assert_eq!(rid.page, 0);
+        assert_eq!(arquivo.get_free_records(), 0);
+        assert_eq!(arquivo.get_page_count(), 3);
+        assert_eq!(arquivo.scan().unwrap().count(), 15);
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    //A register too short to have an nseq is a corrupt page for the binary search, not a panic
+    #[test]
+    fn sorted_search_reports_short_registers() {
+        let file_name = std::env::temp_dir()
+            .join(format!("t02_short_register_{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let schema = Schema::new(vec![
+            Column::new("nseq", ColumnType::UInt32),
+            Column::new("nome", ColumnType::VarString(20)),
+        ])
+        .unwrap();
+        let arquivo = Arquivo::new_with_schema(0, 4, file_name.clone(), schema.clone());
+        arquivo.write_in_file().unwrap();
+        let mut arquivo = Arquivo::open(file_name.clone()).unwrap();
+        arquivo.insert(&registro(&schema, 7, "")).unwrap();
+        arquivo.set_organization(Organization::Sorted).unwrap();
+
+        //Length of slot 0 of page 0 down to 2 bytes
+        let mut file = arquivo.get_file().unwrap();
+        let length = arquivo.page_position(0) + (PAGE_HEADER_SIZE + 4) as u64;
+        file.seek(SeekFrom::Start(length)).unwrap();
+        file.write_all(&2u32.to_be_bytes()).unwrap();
+
+        let reopened = Arquivo::open(file_name.clone()).unwrap();
+        assert!(matches!(
+            reopened.search(7),
+            Err(Error::CorruptPage { page: 0, .. })
+        ));
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    //A stale rid that now holds another register's moved copy cannot delete it
+    #[test]
+    fn delete_by_rid_leaves_moved_registers_alone() {
+        let file_name = std::env::temp_dir()
+            .join(format!("t02_moved_delete_{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let schema = Schema::new(vec![
+            Column::new("nseq", ColumnType::UInt32),
+            Column::new("nome", ColumnType::VarString(100)),
+        ])
+        .unwrap();
+        let arquivo = Arquivo::new_with_schema(0, 4, file_name.clone(), schema.clone());
+        arquivo.write_in_file().unwrap();
+        let mut arquivo = Arquivo::open(file_name.clone()).unwrap();
+        let rids: Vec<Rid> = (0..12)
+            .map(|nseq| arquivo.insert(&registro(&schema, nseq, "")).unwrap())
+            .collect();
+        for (nseq, rid) in rids.iter().enumerate() {
+            let longo = registro(&schema, nseq as u32, &"n".repeat(100));
+            arquivo.update_by_rid(*rid, &longo).unwrap();
+        }
+
+        for page in 0..arquivo.get_page_count() {
+            for slot in 0..16 {
+                let rid = Rid::new(page, slot);
+                if !rids.contains(&rid) {
+                    assert!(matches!(arquivo.delete_by_rid(rid), Err(Error::NotFound)));
+                }
+            }
+        }
+        assert_eq!(arquivo.get_number_of_registers(), 12);
+        for (nseq, rid) in rids.iter().enumerate() {
+            assert_eq!(arquivo.get_by_rid(*rid).unwrap().get_nseq(), nseq as u32);
+        }
+        assert_eq!(arquivo.scan().unwrap().count(), 12);
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    //Many passes with little memory still give a stable order and leave no runs behind
+    #[test]
+    fn sort_by_merges_runs_in_several_passes() {
+        let directory = std::env::temp_dir().join(format!("t02_sort_by_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file_name = directory.join("arquivo").to_str().unwrap().to_owned();
+        let schema = Schema::new(vec![
+            Column::new("nseq", ColumnType::UInt32),
+            Column::new("nome", ColumnType::VarString(20)),
+        ])
+        .unwrap();
+        let arquivo = Arquivo::new_with_schema(0, 3, file_name.clone(), schema.clone());
+        arquivo.write_in_file().unwrap();
+        let mut arquivo = Arquivo::open(file_name.clone()).unwrap();
+        for nseq in 0..40 {
+            let nome = ((nseq * 7) % 5).to_string();
+            arquivo.insert(&registro(&schema, nseq, &nome)).unwrap();
+        }
+
+        let copy_name = directory.join("ordenado").to_str().unwrap().to_owned();
+        let (ordenado, report) = arquivo.sort_by("nome", copy_name.clone()).unwrap();
+        assert_eq!(report.get_runs(), 14);
+        assert!(report.get_passes() > 2);
+        assert_eq!(report.get_run_reads(), report.get_run_writes());
+        assert_eq!(report.get_memory_registers(), 3);
+        assert_eq!(
+            report.get_memory_bytes(),
+            3 * schema.max_row_size() as u64 + arquivo.page_size()
+        );
+        let sorted: Vec<(String, u32)> = ordenado
+            .scan()
+            .unwrap()
+            .map(|scanned| {
+                let registro = scanned.unwrap().1;
+                (registro.get_values()[1].to_string(), registro.get_nseq())
+            })
+            .collect();
+        let mut expected: Vec<(String, u32)> = (0..40)
+            .map(|nseq| (((nseq * 7) % 5).to_string(), nseq))
+            .collect();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(sorted, expected);
+        assert_eq!(
+            Arquivo::open(copy_name).unwrap().get_number_of_registers(),
+            40
+        );
+
+        assert!(arquivo
+            .sort_by("saldo", directory.join("nada").to_str().unwrap().to_owned())
+            .is_err());
+        let mut files: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["arquivo", "ordenado"]);
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 }
@@ -0,0 +1,431 @@
+use crate::codec::{pad_text, unpad_text};
+use crate::error::{Error, Result};
+use rand::{distributions::Alphanumeric, Rng};
+use std::cmp::Ordering;
+use std::fmt;
+
+//Type of a column and how it is laid out in a row
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    UInt32,
+    Int32,
+    Int64,
+    Float64,
+    Bool,
+    Date,
+    //Always takes this many bytes of UTF-8, shorter texts are padded with zeros (the rules of
+    //codec::ByteString, so the text cannot have a '\0')
+    FixedString(u16),
+    //Length (u16) followed by the bytes, up to this many
+    VarString(u16),
+}
+
+impl ColumnType {
+    //Bytes the column takes in a row, the longest possible for VarString
+    pub fn max_width(&self) -> usize {
+        match self {
+            ColumnType::UInt32 | ColumnType::Int32 | ColumnType::Date => 4,
+            ColumnType::Int64 | ColumnType::Float64 => 8,
+            ColumnType::Bool => 1,
+            ColumnType::FixedString(length) => *length as usize,
+            ColumnType::VarString(max_length) => 2 + *max_length as usize,
+        }
+    }
+
+    //Stored in the file header as a tag and the length of strings
+    fn to_header(self) -> (u8, u16) {
+        match self {
+            ColumnType::UInt32 => (0, 0),
+            ColumnType::Int32 => (1, 0),
+            ColumnType::Int64 => (2, 0),
+            ColumnType::Float64 => (3, 0),
+            ColumnType::Bool => (4, 0),
+            ColumnType::Date => (5, 0),
+            ColumnType::FixedString(length) => (6, length),
+            ColumnType::VarString(max_length) => (7, max_length),
+        }
+    }
+
+    fn from_header(tag: u8, length: u16) -> Result<ColumnType> {
+        match tag {
+            0 => Ok(ColumnType::UInt32),
+            1 => Ok(ColumnType::Int32),
+            2 => Ok(ColumnType::Int64),
+            3 => Ok(ColumnType::Float64),
+            4 => Ok(ColumnType::Bool),
+            5 => Ok(ColumnType::Date),
+            6 => Ok(ColumnType::FixedString(length)),
+            7 => Ok(ColumnType::VarString(length)),
+            _ => Err(Error::FormatMismatch("unknown column type in schema")),
+        }
+    }
+
+    fn accepts(&self, value: &Value) -> bool {
+        match (self, value) {
+            (ColumnType::UInt32, Value::UInt32(_))
+            | (ColumnType::Int32, Value::Int32(_))
+            | (ColumnType::Int64, Value::Int64(_))
+            | (ColumnType::Float64, Value::Float64(_))
+            | (ColumnType::Bool, Value::Bool(_))
+            | (ColumnType::Date, Value::Date(_)) => true,
+            (ColumnType::FixedString(length), Value::Text(text)) => {
+                text.len() <= *length as usize && !text.contains('\0')
+            }
+            (ColumnType::VarString(length), Value::Text(text)) => text.len() <= *length as usize,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Column {
+    name: String,
+    column_type: ColumnType,
+}
+
+#[allow(dead_code)]
+impl Column {
+    pub fn new(name: &str, column_type: ColumnType) -> Column {
+        Column {
+            name: name.to_owned(),
+            column_type,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_column_type(&self) -> ColumnType {
+        self.column_type
+    }
+}
+
+//Calendar date, kept as days since 1970-01-01
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    days: i32,
+}
+
+#[allow(dead_code)]
+impl Date {
+    pub fn from_days(days: i32) -> Date {
+        Date { days }
+    }
+
+    //Proleptic gregorian calendar, month and day start at 1
+    pub fn from_ymd(year: i32, month: u32, day: u32) -> Date {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month = month as i32;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i32 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Date {
+            days: era * 146097 + day_of_era - 719468,
+        }
+    }
+
+    pub fn get_days(&self) -> i32 {
+        self.days
+    }
+
+    pub fn to_ymd(self) -> (i32, u32, u32) {
+        let days = self.days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+        let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.to_ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+//Value of a column in a row, both string types hold a Text
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    UInt32(u32),
+    Int32(i32),
+    Int64(i64),
+    Float64(f64),
+    Bool(bool),
+    Date(Date),
+    Text(String),
+}
+
+impl Value {
+    //Order of two values of the same column, floats with total_cmp so NaNs are ordered too.
+    //Values of different types are never in the same column and compare as equal
+    pub fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::UInt32(a), Value::UInt32(b)) => a.cmp(b),
+            (Value::Int32(a), Value::Int32(b)) => a.cmp(b),
+            (Value::Int64(a), Value::Int64(b)) => a.cmp(b),
+            (Value::Float64(a), Value::Float64(b)) => a.total_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Date(a), Value::Date(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::UInt32(value) => write!(f, "{}", value),
+            Value::Int32(value) => write!(f, "{}", value),
+            Value::Int64(value) => write!(f, "{}", value),
+            Value::Float64(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Date(value) => write!(f, "{}", value),
+            Value::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+//Named, typed columns of the rows of an Arquivo. The first column is the key (nseq) used to
+//locate rows and to build the indices, so it must be a UInt32
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    columns: Vec<Column>,
+}
+
+#[allow(dead_code)]
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Result<Schema> {
+        match columns.first() {
+            Some(column) if column.column_type == ColumnType::UInt32 => {}
+            _ => {
+                return Err(Error::InvalidRow(
+                    "the first column of a schema must be a UInt32 key".to_owned(),
+                ))
+            }
+        }
+        for (i, column) in columns.iter().enumerate() {
+            if column.name.is_empty() || column.name.len() > u8::MAX as usize {
+                return Err(Error::InvalidRow(format!(
+                    "column name {:?} must have 1 to 255 bytes",
+                    column.name
+                )));
+            }
+            if columns[..i].iter().any(|other| other.name == column.name) {
+                return Err(Error::InvalidRow(format!(
+                    "column {} appears twice",
+                    column.name
+                )));
+            }
+        }
+        if columns.len() > u16::MAX as usize {
+            return Err(Error::InvalidRow("too many columns".to_owned()));
+        }
+        Ok(Schema { columns })
+    }
+
+    pub fn get_columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    //Longest row the schema can produce, pages are sized with it
+    pub fn max_row_size(&self) -> usize {
+        self.columns
+            .iter()
+            .map(|column| column.column_type.max_width())
+            .sum()
+    }
+
+    //Rows must have one value per column, each of the type of its column
+    pub fn check(&self, values: &[Value]) -> Result<()> {
+        if values.len() != self.columns.len() {
+            return Err(Error::InvalidRow(format!(
+                "row has {} values for {} columns",
+                values.len(),
+                self.columns.len()
+            )));
+        }
+        for (column, value) in self.columns.iter().zip(values) {
+            if !column.column_type.accepts(value) {
+                return Err(Error::InvalidRow(format!(
+                    "value {:?} does not fit column {} ({:?})",
+                    value, column.name, column.column_type
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, values: &[Value]) -> Result<Vec<u8>> {
+        self.check(values)?;
+        let mut buffer = Vec::with_capacity(self.max_row_size());
+        for (column, value) in self.columns.iter().zip(values) {
+            match (column.column_type, value) {
+                (_, Value::UInt32(value)) => buffer.extend_from_slice(&value.to_be_bytes()),
+                (_, Value::Int32(value)) => buffer.extend_from_slice(&value.to_be_bytes()),
+                (_, Value::Int64(value)) => buffer.extend_from_slice(&value.to_be_bytes()),
+                (_, Value::Float64(value)) => buffer.extend_from_slice(&value.to_be_bytes()),
+                (_, Value::Bool(value)) => buffer.push(*value as u8),
+                (_, Value::Date(value)) => buffer.extend_from_slice(&value.days.to_be_bytes()),
+                (ColumnType::FixedString(length), Value::Text(text)) => {
+                    pad_text(text, length as usize, &mut buffer)?
+                }
+                (_, Value::Text(text)) => {
+                    buffer.extend_from_slice(&(text.len() as u16).to_be_bytes());
+                    buffer.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        Ok(buffer)
+    }
+
+    //page is only used to report a corrupt row
+    pub fn decode(&self, page: u64, buffer: &[u8]) -> Result<Vec<Value>> {
+        let corrupt = |reason| Error::CorruptPage { page, reason };
+        let mut values = Vec::with_capacity(self.columns.len());
+        let mut position = 0;
+        let mut take = |length: usize| match buffer.get(position..position + length) {
+            Some(bytes) => {
+                position += length;
+                Ok(bytes)
+            }
+            None => Err(corrupt("row is shorter than its schema")),
+        };
+        for column in &self.columns {
+            let width = column.column_type.max_width();
+            let value = match column.column_type {
+                ColumnType::UInt32 => {
+                    Value::UInt32(u32::from_be_bytes(take(4)?.try_into().unwrap()))
+                }
+                ColumnType::Int32 => Value::Int32(i32::from_be_bytes(take(4)?.try_into().unwrap())),
+                ColumnType::Int64 => Value::Int64(i64::from_be_bytes(take(8)?.try_into().unwrap())),
+                ColumnType::Float64 => {
+                    Value::Float64(f64::from_be_bytes(take(8)?.try_into().unwrap()))
+                }
+                ColumnType::Bool => match take(1)?[0] {
+                    0 => Value::Bool(false),
+                    1 => Value::Bool(true),
+                    _ => return Err(corrupt("bool column is not 0 or 1")),
+                },
+                ColumnType::Date => Value::Date(Date::from_days(i32::from_be_bytes(
+                    take(4)?.try_into().unwrap(),
+                ))),
+                ColumnType::FixedString(_) => {
+                    let text = unpad_text(take(width)?).ok_or(corrupt("text is not utf-8"))?;
+                    Value::Text(text.to_owned())
+                }
+                ColumnType::VarString(max_length) => {
+                    let length = u16::from_be_bytes(take(2)?.try_into().unwrap());
+                    if length > max_length {
+                        return Err(corrupt("text is longer than its column"));
+                    }
+                    let bytes = take(length as usize)?;
+                    Value::Text(text_from(bytes).ok_or(corrupt("text is not utf-8"))?)
+                }
+            };
+            values.push(value);
+        }
+        if position != buffer.len() {
+            return Err(corrupt("row is longer than its schema"));
+        }
+        Ok(values)
+    }
+
+    //Random row with this key, strings are filled with alphanumeric chars
+    pub fn random_row(&self, key: u32) -> Vec<Value> {
+        let mut rng = rand::thread_rng();
+        let mut values = vec![Value::UInt32(key)];
+        for column in &self.columns[1..] {
+            let value = match column.column_type {
+                ColumnType::UInt32 => Value::UInt32(rng.gen()),
+                ColumnType::Int32 => Value::Int32(rng.gen()),
+                ColumnType::Int64 => Value::Int64(rng.gen()),
+                ColumnType::Float64 => Value::Float64(rng.gen_range(0.0..1000.0)),
+                ColumnType::Bool => Value::Bool(rng.gen()),
+                ColumnType::Date => Value::Date(Date::from_days(rng.gen_range(0..20000))),
+                ColumnType::FixedString(length) => Value::Text(random_text(length as usize)),
+                ColumnType::VarString(max_length) => {
+                    Value::Text(random_text(rng.gen_range(0..=max_length as usize)))
+                }
+            };
+            values.push(value);
+        }
+        values
+    }
+
+    //Column count (u16), then for each column its name length (u8), name, type tag (u8) and
+    //string length (u16)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = (self.columns.len() as u16).to_be_bytes().to_vec();
+        for column in &self.columns {
+            let (tag, length) = column.column_type.to_header();
+            buffer.push(column.name.len() as u8);
+            buffer.extend_from_slice(column.name.as_bytes());
+            buffer.push(tag);
+            buffer.extend_from_slice(&length.to_be_bytes());
+        }
+        buffer
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<Schema> {
+        let mismatch = || Error::FormatMismatch("schema in the file header is truncated");
+        let mut position = 0;
+        let mut take = |length: usize| match buffer.get(position..position + length) {
+            Some(bytes) => {
+                position += length;
+                Ok(bytes)
+            }
+            None => Err(mismatch()),
+        };
+        let count = u16::from_be_bytes(take(2)?.try_into().unwrap());
+        let mut columns = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name_length = take(1)?[0] as usize;
+            let name = text_from(take(name_length)?)
+                .ok_or(Error::FormatMismatch("column name is not utf-8"))?;
+            let tag = take(1)?[0];
+            let length = u16::from_be_bytes(take(2)?.try_into().unwrap());
+            columns.push(Column::new(&name, ColumnType::from_header(tag, length)?));
+        }
+        Schema::new(columns)
+            .map_err(|_| Error::FormatMismatch("schema in the file header is invalid"))
+    }
+}
+
+//The layout of the original registers: nseq and a nome of 96 chars, 100 bytes per row
+impl Default for Schema {
+    fn default() -> Schema {
+        Schema {
+            columns: vec![
+                Column::new("nseq", ColumnType::UInt32),
+                Column::new("nome", ColumnType::FixedString(96)),
+            ],
+        }
+    }
+}
+
+fn text_from(bytes: &[u8]) -> Option<String> {
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+fn random_text(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}